@@ -0,0 +1,49 @@
+use crate::record::Command;
+
+/// Sets and removes which `KvStore::write` applies all together.
+///
+/// ```
+/// use kvs::WriteBatch;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove("list1:item").set("list2:item", "value");
+/// ```
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) cmds: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.cmds.push(Command::Set {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+            expires: None,
+        });
+        self
+    }
+
+    /// Removes `key`, which must exist once the commands before are applied.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.cmds.push(Command::Remove {
+            key: key.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Returns the number of commands in the batch.
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    /// Returns whether the batch has no command.
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+}
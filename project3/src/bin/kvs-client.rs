@@ -0,0 +1,87 @@
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::{KvsClient, KvsError};
+use std::net::SocketAddr;
+use std::process::exit;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+
+fn main() {
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP-PORT")
+        .help("Sets the server address")
+        .takes_value(true)
+        .default_value(DEFAULT_LISTENING_ADDRESS);
+
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set the value of a string key to a string")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The string value of the key")
+                        .required(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get the value of a string key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove the value of a string key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg),
+        )
+        .get_matches();
+
+    let (name, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let addr: SocketAddr = match matches.value_of("addr").unwrap().parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid server address: {}", e);
+            exit(1);
+        }
+    };
+    let key = matches.value_of("KEY").unwrap().to_owned();
+
+    let result = KvsClient::connect(addr).and_then(|mut client| match name {
+        "set" => {
+            let value = matches.value_of("VALUE").unwrap().to_owned();
+            client.set(key, value)
+        }
+        "get" => {
+            if let Some(value) = client.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+            Ok(())
+        }
+        "rm" => client.remove(key),
+        _ => unreachable!(),
+    });
+
+    match result {
+        Ok(()) => {}
+        Err(KvsError::KeyNotFound) => {
+            eprintln!("Key not found");
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use clap::{App, Arg};
+use env_logger::Target;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{resolve_engine, KvStore, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
+use log::{error, info, LevelFilter};
+use std::env::current_dir;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::thread::available_parallelism;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+
+fn main() {
+    env_logger::builder()
+        .filter_level(LevelFilter::Info)
+        .target(Target::Stderr)
+        .init();
+
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("Sets the listening address")
+                .takes_value(true)
+                .default_value(DEFAULT_LISTENING_ADDRESS),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Sets the storage engine")
+                .takes_value(true)
+                .possible_values(&["kvs", "sled"]),
+        )
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .value_name("POOL-NAME")
+                .help("Sets the thread pool serving connections")
+                .takes_value(true)
+                .possible_values(&["naive", "shared-queue", "rayon"])
+                .default_value("shared-queue"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Sets the number of threads in the pool [default: number of CPUs]")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = match matches.value_of("addr").unwrap().parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid listening address: {}", e);
+            exit(1);
+        }
+    };
+
+    let threads = match matches.value_of("threads") {
+        Some(threads) => match threads.parse() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                error!("Invalid number of threads: {}", threads);
+                exit(1);
+            }
+        },
+        None => available_parallelism().map_or(1, |n| n.get() as u32),
+    };
+
+    let pool = matches.value_of("pool").unwrap();
+    if let Err(e) = run(addr, matches.value_of("engine"), pool, threads) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(addr: SocketAddr, engine: Option<&str>, pool: &str, threads: u32) -> Result<()> {
+    let engine = resolve_engine(&current_dir()?, engine)?;
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {}", engine);
+    info!("Thread pool: {} with {} threads", pool, threads);
+    info!("Listening on {}", addr);
+
+    match engine.as_str() {
+        "kvs" => run_with_engine(KvStore::open(current_dir()?)?, addr, pool, threads),
+        "sled" => run_with_engine(
+            SledKvsEngine::new(sled::open(current_dir()?)?),
+            addr,
+            pool,
+            threads,
+        ),
+        _ => Err(KvsError::WrongEngine(engine)),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(
+    engine: E,
+    addr: SocketAddr,
+    pool: &str,
+    threads: u32,
+) -> Result<()> {
+    match pool {
+        "naive" => run_with::<E, NaiveThreadPool>(engine, addr, threads),
+        "shared-queue" => run_with::<E, SharedQueueThreadPool>(engine, addr, threads),
+        "rayon" => run_with::<E, RayonThreadPool>(engine, addr, threads),
+        _ => unreachable!(),
+    }
+}
+
+fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, addr: SocketAddr, threads: u32) -> Result<()> {
+    let server = KvsServer::new(engine, P::new(threads)?);
+    server.run(addr)
+}
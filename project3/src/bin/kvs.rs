@@ -1,9 +1,34 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::KvStore;
-use kvs::KvsError;
-use kvs::Result;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::{
+    check_engine, resolve_engine, CompareAndSwapError, ExportFormat, KvStore, KvsEngine, KvsError,
+    Result, SledKvsEngine,
+};
 use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::ops::Bound;
+use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
+
+/// Subcommands of the `kvs` engine alone.
+const KVS_ONLY: &[&str] = &[
+    "scan",
+    "keys",
+    "count",
+    "cas",
+    "set-if-absent",
+    "rm-if-equals",
+    "ttl",
+    "backup",
+    "restore",
+    "export",
+    "import",
+];
+
+/// Subcommands of the `kvs` engine which only read the store, and so run while another
+/// process has it open.
+const READ_ONLY: &[&str] = &["get", "scan", "keys", "count", "ttl", "backup", "export"];
 
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -13,6 +38,15 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Sets the storage engine")
+                .takes_value(true)
+                .possible_values(&["kvs", "sled"])
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -21,8 +55,20 @@ fn main() -> Result<()> {
                     Arg::with_name("VALUE")
                         .help("The string value of the key")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("DURATION")
+                        .help("Expires the key after DURATION, e.g. 30s, 500ms, 5m, 2h or 1d")
+                        .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Print the time left before a string key expires")
+                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+        )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the value of a string key")
@@ -33,19 +79,402 @@ fn main() -> Result<()> {
                 .about("Remove the value of a string key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List the keys and values in key order, one tab-separated pair per line")
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .value_name("KEY")
+                        .help("Starts at this key, included")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .value_name("KEY")
+                        .help("Stops before this key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Lists at most N pairs")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("List the keys in key order, one per line")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Lists only the keys starting with PREFIX")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("count").about("Count the keys").arg(
+                Arg::with_name("prefix")
+                    .long("prefix")
+                    .value_name("PREFIX")
+                    .help("Counts only the keys starting with PREFIX")
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Set or remove a key only if it has the expected value")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .value_name("VALUE")
+                        .help("The expected value, the key must be missing without it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("VALUE")
+                        .help("The new value, the key is removed without it")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-if-absent")
+                .about("Set the value of a string key only if it is missing")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The string value of the key")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm-if-equals")
+                .about("Remove a string key only if it has the expected value")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The expected value of the key")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the store to a new directory, while it is in use")
+                .arg(
+                    Arg::with_name("DEST")
+                        .help("The directory to copy the store to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Replace the store with a backup")
+                .arg(
+                    Arg::with_name("SRC")
+                        .help("The directory of the backup")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the keys and values to stdout in key order")
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Writes only the keys starting with PREFIX")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set the keys and values read from stdin, as written by export")
+                .arg(format_arg()),
+        )
         .get_matches();
 
+    let dir = current_dir()?;
+    let subcommand = matches.subcommand_name().unwrap();
+    let resolved = check_engine(&dir, matches.value_of("engine")).and_then(|engine| {
+        // the commands `kvs` runs on a read-only store leave the directory as it is
+        if engine == "kvs" && READ_ONLY.contains(&subcommand) {
+            Ok(engine)
+        } else {
+            resolve_engine(&dir, Some(&engine))
+        }
+    });
+    let engine = match resolved {
+        Ok(engine) => engine,
+        Err(e @ KvsError::WrongEngine(_)) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        Err(e) => return Err(e),
+    };
+
+    match engine.as_str() {
+        "kvs" if matches.subcommand_name() == Some("restore") => {
+            let src = matches
+                .subcommand_matches("restore")
+                .unwrap()
+                .value_of("SRC");
+            match KvStore::restore(src.unwrap(), current_dir()?) {
+                Err(e @ KvsError::Locked) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                result => result,
+            }
+        }
+        "kvs" => match open_kvs(matches.subcommand_name().unwrap()) {
+            Ok(store) => run_kvs(store, &matches),
+            Err(e @ KvsError::Locked) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            Err(e) => Err(e),
+        },
+        "sled" => {
+            let subcommand = matches.subcommand_name().unwrap();
+            if KVS_ONLY.contains(&subcommand) {
+                eprintln!("The sled engine does not support {}", subcommand);
+                exit(1);
+            }
+            if let ("set", Some(matches)) = matches.subcommand() {
+                if matches.is_present("ttl") {
+                    eprintln!("The sled engine does not support --ttl");
+                    exit(1);
+                }
+            }
+            run(SledKvsEngine::new(sled::open(current_dir()?)?), &matches)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Opens the store in the current directory for `subcommand`, read-only if it only reads.
+fn open_kvs(subcommand: &str) -> Result<KvStore> {
+    if READ_ONLY.contains(&subcommand) {
+        KvStore::open_read_only(current_dir()?)
+    } else {
+        KvStore::open(current_dir()?)
+    }
+}
+
+/// Runs the subcommands only `KvStore` supports, or else `run`.
+fn run_kvs(store: KvStore, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("scan", Some(matches)) => {
+            let bound = |name, bound: fn(String) -> Bound<String>| {
+                matches
+                    .value_of(name)
+                    .map_or(Bound::Unbounded, |key| bound(key.to_owned()))
+            };
+            let range = (
+                bound("start", Bound::Included),
+                bound("end", Bound::Excluded),
+            );
+            let limit = match matches.value_of("limit").map(str::parse) {
+                None => usize::MAX,
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => {
+                    eprintln!("Invalid limit: {}", matches.value_of("limit").unwrap());
+                    exit(1);
+                }
+            };
+            for pair in store.scan(range).take(limit) {
+                let (key, value) = pair?;
+                println!("{}\t{}", key, value);
+            }
+            Ok(())
+        }
+        ("keys", Some(matches)) => {
+            let prefix = matches.value_of("prefix").unwrap_or("");
+            for key in store.scan_prefix(prefix).keys() {
+                println!("{}", key?);
+            }
+            Ok(())
+        }
+        ("count", Some(matches)) => {
+            match matches.value_of("prefix") {
+                Some(prefix) => println!("{}", store.count_prefix(prefix)),
+                None => println!("{}", store.len()),
+            }
+            Ok(())
+        }
+        ("set", Some(matches)) if matches.is_present("ttl") => {
+            let key = matches.value_of("KEY").unwrap();
+            let value = matches.value_of("VALUE").unwrap();
+            let ttl = matches.value_of("ttl").unwrap();
+            match parse_duration(ttl) {
+                Some(ttl) => store.set_with_ttl(key, value, ttl),
+                None => {
+                    eprintln!("Invalid TTL: {}", ttl);
+                    exit(1);
+                }
+            }
+        }
+        ("ttl", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            match store.ttl(key) {
+                Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+            Ok(())
+        }
+        ("cas", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let expected = matches.value_of("expected").map(str::as_bytes);
+            let new = matches.value_of("new").map(str::as_bytes);
+            check_swapped(store.compare_and_swap(key, expected, new)?)
+        }
+        ("set-if-absent", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let value = matches.value_of("VALUE").unwrap();
+            check_swapped(store.set_if_absent(key, value)?)
+        }
+        ("rm-if-equals", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let value = matches.value_of("VALUE").unwrap();
+            check_swapped(store.remove_if_equals(key, value)?)
+        }
+        ("export", Some(matches)) => {
+            let prefix = matches.value_of("prefix").unwrap_or("");
+            let stdout = io::stdout();
+            store.export(
+                prefix,
+                export_format(matches),
+                BufWriter::new(stdout.lock()),
+            )?;
+            Ok(())
+        }
+        ("import", Some(matches)) => {
+            match store.import(export_format(matches), io::stdin().lock()) {
+                Ok(_) => Ok(()),
+                Err(e @ KvsError::InvalidImport { .. }) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        ("backup", Some(matches)) => {
+            let dest = Path::new(matches.value_of("DEST").unwrap());
+            match backup(&store, dest) {
+                Ok(()) => Ok(()),
+                Err(e @ KvsError::StoreExists) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        _ => run(store, matches),
+    }
+}
+
+/// Writes a checkpoint of `store` to `dest`, as a data directory of the `kvs` engine.
+///
+/// The checkpoint goes to a sibling of `dest` first, which is only renamed to `dest` once
+/// complete, so that a backup which fails leaves nothing in the way of the next one.
+fn backup(store: &KvStore, dest: &Path) -> Result<()> {
+    if dest.is_dir() && dest.read_dir()?.next().is_some() {
+        return Err(KvsError::StoreExists);
+    }
+    let name = dest.file_name().ok_or_else(|| {
+        let message = format!("Invalid backup directory: {}", dest.display());
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    })?;
+    let tmp = dest.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    // left by a backup which failed
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    store.checkpoint(&tmp)?;
+    resolve_engine(&tmp, Some("kvs"))?;
+    fs::rename(&tmp, dest)?;
+    let parent = dest
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    File::open(parent.unwrap_or_else(|| Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+/// The `--format` option of `export` and `import`.
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .help("The text format of the keys and values")
+        .possible_values(&["jsonl", "csv"])
+        .default_value("jsonl")
+}
+
+/// Returns the format given to `export` or `import`.
+fn export_format(matches: &ArgMatches) -> ExportFormat {
+    match matches.value_of("format") {
+        Some("csv") => ExportFormat::Csv,
+        _ => ExportFormat::JsonLines,
+    }
+}
+
+/// Parses a duration such as `30s`, a number followed by `ms`, `s`, `m`, `h` or `d`, where
+/// a number alone is in seconds.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => return Some(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+/// Prints the current value and fails if a conditional write did not match it.
+fn check_swapped(swapped: std::result::Result<(), CompareAndSwapError>) -> Result<()> {
+    if let Err(CompareAndSwapError { current }) = swapped {
+        match current {
+            Some(current) => println!("{}", String::from_utf8(current)?),
+            None => println!("Key not found"),
+        }
+        exit(1);
+    }
+    Ok(())
+}
+
+fn run(engine: impl KvsEngine, matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
-            store.set(key.to_string(), value.to_string())?;
+            engine.set(key.to_string(), value.to_string())?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
-            if let Some(value) = store.get(key.to_string())? {
+            if let Some(value) = engine.get(key.to_string())? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
@@ -53,8 +482,7 @@ fn main() -> Result<()> {
         }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
-            match store.remove(key.to_owned()) {
+            match engine.remove(key.to_owned()) {
                 Ok(_) => {}
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
@@ -0,0 +1,50 @@
+use crate::common::{Request, Response};
+use crate::{KvsError, Result};
+use serde::Deserialize;
+use serde_json::de::{Deserializer, IoRead};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// Key value store client
+pub struct KvsClient {
+    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to `addr` to access `KvsServer`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let tcp_reader = TcpStream::connect(addr)?;
+        let tcp_writer = tcp_reader.try_clone()?;
+        Ok(KvsClient {
+            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            writer: BufWriter::new(tcp_writer),
+        })
+    }
+
+    /// Gets the value of a given key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(&Request::Get { key })
+    }
+
+    /// Sets the value of a string key in the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(&Request::Set { key, value })?;
+        Ok(())
+    }
+
+    /// Removes a string key in the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Remove { key })?;
+        Ok(())
+    }
+
+    fn request(&mut self, req: &Request) -> Result<Option<String>> {
+        serde_json::to_writer(&mut self.writer, req)?;
+        self.writer.flush()?;
+        match Response::deserialize(&mut self.reader)? {
+            Response::Ok(value) => Ok(value),
+            Response::Err(err) => Err(KvsError::from(err)),
+        }
+    }
+}
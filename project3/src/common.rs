@@ -0,0 +1,65 @@
+//! Wire protocol between `kvs-client` and `kvs-server`.
+//!
+//! Each connection carries a stream of JSON-serialized [`Request`]s from the client, and the
+//! server answers every request with exactly one JSON-serialized [`Response`], in order.
+
+use crate::KvsError;
+use serde::{Deserialize, Serialize};
+
+/// A request sent from the client to the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Gets the value of `key`.
+    Get {
+        /// The key to look up.
+        key: String,
+    },
+    /// Sets `key` to `value`.
+    Set {
+        /// The key to set.
+        key: String,
+        /// The new value of the key.
+        value: String,
+    },
+    /// Removes `key`.
+    Remove {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+/// The server's answer to a [`Request`].
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The request succeeded. Carries the value for `Get`, and `None` otherwise.
+    Ok(Option<String>),
+    /// The request failed.
+    Err(ErrorResponse),
+}
+
+/// An engine error, in a form that can be sent over the wire.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    /// The key to remove does not exist.
+    KeyNotFound,
+    /// Any other failure on the server, with its message.
+    Other(String),
+}
+
+impl From<KvsError> for ErrorResponse {
+    fn from(err: KvsError) -> Self {
+        match err {
+            KvsError::KeyNotFound => ErrorResponse::KeyNotFound,
+            err => ErrorResponse::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<ErrorResponse> for KvsError {
+    fn from(err: ErrorResponse) -> Self {
+        match err {
+            ErrorResponse::KeyNotFound => KvsError::KeyNotFound,
+            ErrorResponse::Other(msg) => KvsError::Server(msg),
+        }
+    }
+}
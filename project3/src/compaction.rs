@@ -0,0 +1,301 @@
+//! Compaction of the sealed logs of a `KvStore`, run in the background as the garbage grows.
+
+use crate::hint::{hint_file_path, write_hint_file};
+use crate::kv::{
+    log_file_path, sorted_gen_list, sync_dir, BuffWriterWithPos, CommandPos, History, Index,
+    KvStoreReader, KvStoreWriter, Version,
+};
+use crate::options::Retention;
+use crate::record::now_millis;
+use crate::snapshot::SnapshotRegistry;
+use crate::Result;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::error;
+use std::collections::BTreeMap;
+use std::fs::{self, read_dir, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Moves the live records of sealed logs into a new compacted log, along with the old
+/// versions kept by the retention policy.
+pub(crate) struct Compactor {
+    pub(crate) path: Arc<PathBuf>,
+    pub(crate) index: Arc<Index>,
+    pub(crate) history: Option<Arc<History>>,
+    pub(crate) reader: KvStoreReader,
+    pub(crate) writer: Arc<Mutex<KvStoreWriter>>,
+    pub(crate) snapshots: Arc<SnapshotRegistry>,
+    // only one compaction runs at a time
+    pub(crate) lock: Mutex<()>,
+}
+
+impl Compactor {
+    /// Compacts the sealed logs into a new generation.
+    ///
+    /// The writer lock is only held to seal the current log and to swap the index
+    /// entries, so writes keep going while the live records are copied.
+    ///
+    /// The records are copied in the order of their sequence numbers, so that replaying the
+    /// compacted log rebuilds the old versions as well.
+    pub(crate) fn compact(&self) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let now = now_millis();
+        let (compact_gen, last_seq, buffer_size, retention) = {
+            let mut writer = self.writer.lock().unwrap();
+            let options = writer.options;
+            (
+                writer.seal(now)?,
+                writer.seq,
+                options.write_buffer_size,
+                options.retention,
+            )
+        };
+
+        // the records of the sealed logs to keep, and the expired values to drop
+        let mut moved = Vec::new();
+        let mut records = Vec::new();
+        for entry in self.index.iter() {
+            let cmd_pos = entry.value().load();
+            if cmd_pos.gen >= compact_gen {
+                continue;
+            }
+            let version = Version {
+                cmd_pos,
+                removed: false,
+            };
+            // an expired value is dropped along with its key
+            if cmd_pos.is_expired(now) {
+                moved.push((entry.key().clone(), version, None));
+            } else {
+                records.push((entry.key().clone(), version));
+            }
+        }
+        if let Some(history) = &self.history {
+            let mut history = history.lock().unwrap();
+            prune_history(&mut history, &self.index, retention, now);
+            // an expired value becomes the newest old version of a key which keeps others,
+            // so that replaying the compacted log does not bring an older one back
+            moved.retain(|(key, version, _)| {
+                let versions = match history.get_mut(key) {
+                    Some(versions) => versions,
+                    None => return true,
+                };
+                match self.index.get(key) {
+                    Some(entry) if entry.value().load() == version.cmd_pos => {
+                        entry.remove();
+                        versions.push(*version);
+                        false
+                    }
+                    _ => true,
+                }
+            });
+            for (key, versions) in history.iter() {
+                let sealed = versions.iter().filter(|v| v.cmd_pos.gen < compact_gen);
+                records.extend(sealed.map(|&version| (key.clone(), version)));
+            }
+        }
+        records.sort_unstable_by_key(|(_, version)| version.cmd_pos.seq);
+
+        // copy them to the compaction file
+        let tmp_path = compaction_file_path(&self.path, compact_gen);
+        let mut compact_writer =
+            BuffWriterWithPos::with_capacity(buffer_size, File::create(&tmp_path)?)?;
+        for (key, version) in records {
+            let cmd_pos = version.cmd_pos;
+            let pos = compact_writer.pos;
+            let len = self.reader.read_and(cmd_pos, |mut reader_take| {
+                Ok(io::copy(&mut reader_take, &mut compact_writer)?)
+            })?;
+            let new_pos = CommandPos {
+                gen: compact_gen,
+                pos,
+                len,
+                ..cmd_pos
+            };
+            moved.push((key, version, Some(new_pos)));
+        }
+        let compact_len = compact_writer.pos;
+        compact_writer.flush()?;
+        compact_writer.writer.get_ref().sync_all()?;
+        drop(compact_writer);
+        fs::rename(tmp_path, log_file_path(&self.path, compact_gen))?;
+        // the compacted log must be durable under its name before the logs it replaces go
+        sync_dir(&self.path)?;
+
+        // point the index at the copies, unless a key was overwritten or removed meanwhile,
+        // and the history at the copies of the versions it still keeps
+        let mut hint_entries = Vec::with_capacity(moved.len());
+        {
+            let _writer = self.writer.lock().unwrap();
+            let mut history = self.history.as_ref().map(|history| history.lock().unwrap());
+            for (key, version, new_pos) in moved {
+                let old_pos = version.cmd_pos;
+                let new_pos = match new_pos {
+                    Some(new_pos) => new_pos,
+                    None => {
+                        if let Some(entry) = self.index.get(&key) {
+                            if entry.value().load() == old_pos {
+                                entry.remove();
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let in_index = !version.removed
+                    && self.index.get(&key).is_some_and(|entry| {
+                        entry.value().compare_exchange(old_pos, new_pos).is_ok()
+                    });
+                let in_history = !in_index
+                    && history
+                        .as_mut()
+                        .and_then(|history| history.get_mut(&key))
+                        .and_then(|versions| versions.iter_mut().find(|v| v.cmd_pos == old_pos))
+                        .map(|kept| kept.cmd_pos = new_pos)
+                        .is_some();
+                if in_index || in_history {
+                    let cmd_pos = new_pos;
+                    hint_entries.push((key, Version { cmd_pos, ..version }));
+                }
+            }
+        }
+        write_hint_file(
+            &self.path,
+            compact_gen,
+            compact_len,
+            last_seq,
+            &hint_entries,
+        )?;
+
+        // remove stale files once no read can still be using them
+        self.reader.safe_point.store(compact_gen, Ordering::SeqCst);
+        self.reader.reads.wait_for_readers();
+        let stale_gen = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compact_gen);
+        for gen in stale_gen {
+            self.snapshots.remove_log(&self.path, gen)?;
+            let hint_path = hint_file_path(&self.path, gen);
+            if hint_path.exists() {
+                fs::remove_file(hint_path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The thread running compactions in the background.
+pub(crate) struct BackgroundCompaction {
+    trigger: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundCompaction {
+    pub(crate) fn start(compactor: Arc<Compactor>) -> Result<Self> {
+        // one pending request is enough, the compaction covers every sealed log
+        let (trigger, rx) = crossbeam_channel::bounded(1);
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || run_compactions(&compactor, rx))?;
+        Ok(BackgroundCompaction {
+            trigger: Some(trigger),
+            handle: Some(handle),
+        })
+    }
+
+    /// Requests a compaction, unless one is already pending.
+    pub(crate) fn trigger(&self) {
+        if let Some(trigger) = &self.trigger {
+            if let Err(TrySendError::Disconnected(_)) = trigger.try_send(()) {
+                error!("The compaction thread is gone");
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundCompaction {
+    fn drop(&mut self) {
+        // closing the channel stops the thread, after the compaction in progress
+        self.trigger.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+fn run_compactions(compactor: &Compactor, rx: Receiver<()>) {
+    for () in rx {
+        // the garbage may have been dropped by a compaction since the request
+        if !compactor.writer.lock().unwrap().needs_compaction() {
+            continue;
+        }
+        if let Err(e) = compactor.compact() {
+            error!("Background compaction failed: {}", e);
+        }
+    }
+}
+
+/// Drop the old versions in `history` that `retention` no longer keeps at `now`.
+///
+/// A version is superseded when the next one is written, the newest by the current value of
+/// its key. Without a current value, the versions are as old as the newest of them.
+fn prune_history(
+    history: &mut BTreeMap<Vec<u8>, Vec<Version>>,
+    index: &Index,
+    retention: Retention,
+    now: u64,
+) {
+    history.retain(|key, versions| {
+        let dropped = match retention {
+            Retention::Off => versions.len(),
+            Retention::Versions(count) => versions.len().saturating_sub(count),
+            Retention::Age(age) => {
+                let age = u64::try_from(age.as_millis()).unwrap_or(u64::MAX);
+                let oldest = now.saturating_sub(age);
+                let last = versions.last().map_or(0, |version| version.cmd_pos.time);
+                let end = index
+                    .get(key)
+                    .map_or(last, |entry| entry.value().load().time);
+                let superseded = |i: usize| versions.get(i + 1).map_or(end, |v| v.cmd_pos.time);
+                (0..versions.len())
+                    .find(|&i| superseded(i) >= oldest)
+                    .unwrap_or(versions.len())
+            }
+        };
+        versions.drain(..dropped);
+        // a removal with nothing older to remove reads like no version at all
+        let removals = versions
+            .iter()
+            .take_while(|version| version.removed)
+            .count();
+        versions.drain(..removals);
+        !versions.is_empty()
+    });
+}
+
+/// Path of the compaction file of `gen` while it is being written.
+fn compaction_file_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", gen))
+}
+
+/// Remove the files of compactions interrupted by a crash, and the compacted logs kept for
+/// the snapshots of a store which was not closed.
+///
+/// The logs they were compacting are still in place, so nothing is lost.
+pub(crate) fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let unfinished = path
+            .extension()
+            .is_some_and(|ext| ext == "compacting" || ext == "tmp" || ext == "stale");
+        if path.is_file() && unfinished {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
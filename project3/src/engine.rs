@@ -0,0 +1,67 @@
+use crate::{KvsError, Result};
+use std::fs;
+use std::path::Path;
+
+/// Trait for a key value storage engine.
+///
+/// Engines are cheap to clone, and every clone shares the same underlying store, so a handle
+/// can be moved into each thread that needs one.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> Result<()>;
+}
+
+/// Name of the file recording which engine created a data directory.
+const ENGINE_FILE: &str = "engine";
+
+/// Resolves the engine to use for the data directory `dir` and records it there.
+///
+/// Without an explicit `requested` engine, the engine that created the directory is reused,
+/// falling back to `kvs` for a new directory.
+///
+/// # Errors
+///
+/// It returns `KvsError::WrongEngine` if `dir` was created by a different engine.
+pub fn resolve_engine(dir: &Path, requested: Option<&str>) -> Result<String> {
+    let engine = check_engine(dir, requested)?;
+    fs::write(dir.join(ENGINE_FILE), &engine)?;
+    Ok(engine)
+}
+
+/// Resolves the engine to use for the data directory `dir` like `resolve_engine`, without
+/// recording it, for callers which only read the directory.
+///
+/// # Errors
+///
+/// It returns `KvsError::WrongEngine` if `dir` was created by a different engine.
+pub fn check_engine(dir: &Path, requested: Option<&str>) -> Result<String> {
+    let engine_file = dir.join(ENGINE_FILE);
+    let current = if engine_file.exists() {
+        Some(fs::read_to_string(&engine_file)?.trim().to_owned())
+    } else {
+        None
+    };
+
+    match (requested, current) {
+        (Some(requested), Some(current)) if requested != current => {
+            Err(KvsError::WrongEngine(current))
+        }
+        (Some(requested), _) => Ok(requested.to_owned()),
+        (None, Some(current)) => Ok(current),
+        (None, None) => Ok("kvs".to_owned()),
+    }
+}
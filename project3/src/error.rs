@@ -1,10 +1,23 @@
 use std::io;
 use std::result;
+use std::string::FromUtf8Error;
 use thiserror::Error;
 
 /// Result type for kvs.
 pub type Result<T> = result::Result<T, KvsError>;
 
+/// Result of a conditional write, which fails with the current value of the key if it does
+/// not have the expected one.
+pub type CompareAndSwapResult = Result<result::Result<(), CompareAndSwapError>>;
+
+/// A conditional write found another value than the expected one, and wrote nothing.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("Current value does not match the expected one")]
+pub struct CompareAndSwapError {
+    /// The current value of the key, `None` if it is missing.
+    pub current: Option<Vec<u8>>,
+}
+
 /// Error type for kvs.
 #[derive(Debug, Error)]
 pub enum KvsError {
@@ -21,4 +34,55 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[error("Unexpected command type")]
     UnexpectedCommandType,
+    /// A log record is truncated, fails its checksum or cannot be decoded.
+    #[error("Corrupted record in {gen}.log at offset {offset}")]
+    Corruption {
+        /// Generation number of the log file.
+        gen: u64,
+        /// Offset of the bad record in the log file.
+        offset: u64,
+    },
+    /// The `format` file of the data directory names an unknown codec version, or there is
+    /// none and the logs are in no known format, which reads as the version `none`.
+    #[error("Unknown data format version: {0}")]
+    UnknownFormat(String),
+    /// Sled error.
+    #[error("sled failure: {0}")]
+    Sled(#[from] sled::Error),
+    /// Key or value is invalid UTF-8 sequence.
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[from] FromUtf8Error),
+    /// The data directory was created by a different engine.
+    #[error("Wrong engine: data directory was created by `{0}`")]
+    WrongEngine(String),
+    /// Failure to build a rayon thread pool.
+    #[error("thread pool failure: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    /// The data directory is locked by another `KvStore`.
+    #[error("Data directory is in use by another store")]
+    Locked,
+    /// The destination of a checkpoint already holds a store.
+    #[error("Directory already holds a store")]
+    StoreExists,
+    /// A read-only store found a restore of its directory left unfinished, which opening it
+    /// for writing finishes.
+    #[error("Data directory holds an unfinished restore")]
+    UnfinishedRestore,
+    /// A line given to `KvStore::import` does not hold a pair in the expected format.
+    #[error("Invalid pair on line {line}: {reason}")]
+    InvalidImport {
+        /// Number of the line, from 1.
+        line: u64,
+        /// What is wrong with it.
+        reason: String,
+    },
+    /// A write to a store opened read-only.
+    #[error("Store is read-only")]
+    ReadOnly,
+    /// A key read by a transaction was written by someone else before it committed.
+    #[error("Transaction conflicts with a concurrent write")]
+    Conflict,
+    /// Error reported by the remote server.
+    #[error("server error: {0}")]
+    Server(String),
 }
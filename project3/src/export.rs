@@ -0,0 +1,239 @@
+//! Export of the pairs of a `KvStore` as text, and import of them back.
+//!
+//! JSON Lines holds one pair per line, as `{"key":"key1","value":"value1"}`. A key or value
+//! which is not UTF-8 is an array of its bytes instead of a string.
+//!
+//! CSV holds one pair per record, with no header: the key, then the value. Backslashes are
+//! doubled, and the bytes which are not UTF-8 are written as `\xHH` in hexadecimal. Fields
+//! holding a comma, a quote or a line break are quoted, with their quotes doubled.
+
+use crate::{KvStore, KvsError, Result, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::mem;
+
+/// Number of pairs `KvStore::import` writes in each batch.
+const IMPORT_BATCH_LEN: usize = 1000;
+
+/// Text formats of `KvStore::export` and `KvStore::import`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma-separated values.
+    Csv,
+}
+
+/// A pair as a line of JSON Lines.
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: JsonBytes,
+    value: JsonBytes,
+}
+
+/// Bytes in JSON: a string if they are UTF-8, else an array of numbers.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonBytes {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for JsonBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => JsonBytes::Text(text),
+            Err(e) => JsonBytes::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+impl From<JsonBytes> for Vec<u8> {
+    fn from(bytes: JsonBytes) -> Self {
+        match bytes {
+            JsonBytes::Text(text) => text.into_bytes(),
+            JsonBytes::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+impl KvStore {
+    /// Writes the pairs whose keys start with `prefix` to `writer` in `format`, in key order,
+    /// and returns their number.
+    ///
+    /// The pairs are read as the export goes, like with `scan`.
+    pub fn export(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        format: ExportFormat,
+        mut writer: impl Write,
+    ) -> Result<u64> {
+        let mut count = 0;
+        for pair in self.scan_prefix(prefix).bytes() {
+            let (key, value) = pair?;
+            match format {
+                ExportFormat::JsonLines => {
+                    let pair = JsonPair {
+                        key: key.into(),
+                        value: value.into(),
+                    };
+                    serde_json::to_writer(&mut writer, &pair)?;
+                    writeln!(writer)?;
+                }
+                ExportFormat::Csv => {
+                    writeln!(writer, "{},{}", csv_field(&key), csv_field(&value))?;
+                }
+            }
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Sets the pairs read from `reader` in `format`, as written by `export`, and returns
+    /// their number.
+    ///
+    /// The pairs are written in batches of a thousand. Empty lines are skipped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidImport` for a line which does not hold a pair. The
+    /// batches before it are written, and the others are not.
+    pub fn import(&self, format: ExportFormat, mut reader: impl BufRead) -> Result<u64> {
+        let mut count = 0;
+        let mut line = 0;
+        let mut batch = WriteBatch::new();
+        loop {
+            let pair = match format {
+                ExportFormat::JsonLines => read_json_pair(&mut reader, &mut line)?,
+                ExportFormat::Csv => read_csv_pair(&mut reader, &mut line)?,
+            };
+            let (key, value) = match pair {
+                Some(pair) => pair,
+                None => break,
+            };
+            batch.set(key, value);
+            count += 1;
+            if batch.len() == IMPORT_BATCH_LEN {
+                self.write(mem::take(&mut batch))?;
+            }
+        }
+        self.write(batch)?;
+        Ok(count)
+    }
+}
+
+/// Reads the next pair of JSON Lines, or `None` at the end of `reader`. `line` is the
+/// number of the last line read.
+fn read_json_pair<R: BufRead>(
+    reader: &mut R,
+    line: &mut u64,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut text = String::new();
+    loop {
+        text.clear();
+        if reader.read_line(&mut text)? == 0 {
+            return Ok(None);
+        }
+        *line += 1;
+        if !text.trim().is_empty() {
+            break;
+        }
+    }
+    let pair: JsonPair = serde_json::from_str(&text).map_err(|e| KvsError::InvalidImport {
+        line: *line,
+        reason: e.to_string(),
+    })?;
+    Ok(Some((pair.key.into(), pair.value.into())))
+}
+
+/// Reads the next CSV record as a pair, or `None` at the end of `reader`. `line` is the
+/// number of the last line read.
+fn read_csv_pair<R: BufRead>(reader: &mut R, line: &mut u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let invalid = |line: u64, reason: &str| KvsError::InvalidImport {
+        line,
+        reason: reason.to_owned(),
+    };
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut text = String::new();
+    loop {
+        text.clear();
+        if reader.read_line(&mut text)? == 0 {
+            if quoted {
+                return Err(invalid(*line, "unterminated quoted field"));
+            }
+            return Ok(None);
+        }
+        *line += 1;
+        if !quoted && text.trim_end_matches(['\r', '\n']).is_empty() {
+            continue;
+        }
+
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (false, '"') if field.is_empty() => quoted = true,
+                (false, ',') => fields.push(mem::take(&mut field)),
+                (false, '\r' | '\n') => {}
+                (_, c) => field.push(c),
+            }
+        }
+        // a line break within quotes belongs to the field, which goes on on the next line
+        if !quoted {
+            break;
+        }
+    }
+    fields.push(field);
+
+    let (key, value) = match <[String; 2]>::try_from(fields) {
+        Ok([key, value]) => (key, value),
+        Err(_) => return Err(invalid(*line, "expected a key and a value")),
+    };
+    let unescape = |field: &str| unescape_csv(field).ok_or_else(|| invalid(*line, "bad escape"));
+    Ok(Some((unescape(&key)?, unescape(&value)?)))
+}
+
+/// Returns `bytes` as a CSV field, escaped and quoted if need be.
+fn csv_field(bytes: &[u8]) -> String {
+    let mut field = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        field.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            field.push_str(&format!("\\x{:02X}", byte));
+        }
+    }
+    if field.contains([',', '"', '\r', '\n']) {
+        field = format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field
+}
+
+/// Returns the bytes of an unquoted CSV field, or `None` for an invalid escape.
+fn unescape_csv(field: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        bytes.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('\\') {
+            bytes.push(b'\\');
+            rest = after;
+        } else {
+            let hex = rest.strip_prefix('x')?.get(..2)?;
+            if !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &rest[3..];
+        }
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    Some(bytes)
+}
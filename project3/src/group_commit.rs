@@ -0,0 +1,104 @@
+//! Group commit: concurrent writers share one fsync of the log instead of paying for one each.
+
+use crate::Result;
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Batches the fsyncs of the writes to the active log.
+pub(crate) struct GroupCommit {
+    // how long the leader of a group waits for other writers to join it
+    interval: Duration,
+    state: Mutex<State>,
+    synced: Condvar,
+}
+
+struct State {
+    // sequence number of the latest write
+    written: u64,
+    // sequence number of the latest write known to be durable
+    synced: u64,
+    // whether a writer is leading a group
+    syncing: bool,
+    // the active log
+    file: Arc<File>,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(interval: Duration, file: File) -> Arc<Self> {
+        Arc::new(GroupCommit {
+            interval,
+            state: Mutex::new(State {
+                written: 0,
+                synced: 0,
+                syncing: false,
+                file: Arc::new(file),
+            }),
+            synced: Condvar::new(),
+        })
+    }
+
+    /// Makes `file` the log to sync.
+    ///
+    /// The previous log must be synced by the caller, as the writes registered so far
+    /// are then durable as soon as the new log is.
+    pub(crate) fn switch_file(&self, file: File) {
+        self.state.lock().unwrap().file = Arc::new(file);
+    }
+
+    /// Registers a write to the active log, returning the ticket to wait on for its sync.
+    pub(crate) fn register(self: &Arc<Self>) -> SyncTicket {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        SyncTicket {
+            group: Arc::clone(self),
+            seq: state.written,
+        }
+    }
+}
+
+/// A write waiting to be synced by its group.
+pub(crate) struct SyncTicket {
+    group: Arc<GroupCommit>,
+    seq: u64,
+}
+
+impl SyncTicket {
+    /// Waits until the write is durable.
+    ///
+    /// The first writer to wait leads the group: it gives the others `interval` to join,
+    /// then syncs the log for all of them. Must not be called with the writer lock held,
+    /// or no one could join.
+    pub(crate) fn wait(self) -> Result<()> {
+        let group = &self.group;
+        let mut state = group.state.lock().unwrap();
+        loop {
+            if state.synced >= self.seq {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = group.synced.wait(state).unwrap();
+        }
+        state.syncing = true;
+        drop(state);
+
+        thread::sleep(group.interval);
+        let (target, file) = {
+            let state = group.state.lock().unwrap();
+            (state.written, Arc::clone(&state.file))
+        };
+        let result = file.sync_data();
+
+        let mut state = group.state.lock().unwrap();
+        state.syncing = false;
+        if result.is_ok() {
+            state.synced = target;
+        }
+        // on failure, the next writer in line leads a new attempt
+        group.synced.notify_all();
+        Ok(result?)
+    }
+}
@@ -0,0 +1,201 @@
+//! Hint files, which let `KvStore::open` rebuild the index of a compacted generation without
+//! replaying its log.
+//!
+//! A compaction writes `<gen>.hint` next to `<gen>.log`, laid out as
+//!
+//! ```text
+//! +-------------+--------------+---------------+------------+---------+-----------+
+//! | magic: [u8] | log_len: u64 | last_seq: u64 | count: u64 | entries | crc: u32  |
+//! +-------------+--------------+---------------+------------+---------+-----------+
+//! ```
+//!
+//! where each entry is
+//!
+//! ```text
+//! key_len: u32 | pos: u64 | len: u64 | seq: u64 | expires: u64 | time: u64 | removed: u8 | key
+//! ```
+//!
+//! integers are in little endian, and `crc` is the CRC32 of everything before it. `expires`
+//! is 0 for a value which does not expire, and `removed` is 1 for the removal of a key kept
+//! as an old version. The entries are in the order of their sequence numbers. `log_len` is
+//! the length of the log the hint describes, so a hint that no longer matches its log is
+//! ignored. `last_seq` is the sequence number of the last command written before the
+//! compaction, which the records dropped by it may have held.
+
+use crate::kv::{CommandPos, Version};
+use crate::Result;
+use crc32fast::Hasher;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"KVSHINT1";
+
+/// A key and the version of it recorded in the log.
+pub(crate) type HintEntry = (Vec<u8>, Version);
+
+pub(crate) fn hint_file_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Writes the hint file of the log `gen`, which is `log_len` bytes long and holds exactly
+/// the records of `entries`, compacted once the command numbered `last_seq` was written.
+///
+/// The file is written under a temporary name and renamed into place, so a crash never
+/// leaves a partial hint file behind.
+pub(crate) fn write_hint_file(
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+    last_seq: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.hint.tmp", gen));
+    let mut writer = HashWriter {
+        writer: BufWriter::new(File::create(&tmp_path)?),
+        hasher: Hasher::new(),
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&log_len.to_le_bytes())?;
+    writer.write_all(&last_seq.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (key, Version { cmd_pos, removed }) in entries {
+        writer.write_all(&(key.len() as u32).to_le_bytes())?;
+        writer.write_all(&cmd_pos.pos.to_le_bytes())?;
+        writer.write_all(&cmd_pos.len.to_le_bytes())?;
+        writer.write_all(&cmd_pos.seq.to_le_bytes())?;
+        writer.write_all(&cmd_pos.expires.unwrap_or(0).to_le_bytes())?;
+        writer.write_all(&cmd_pos.time.to_le_bytes())?;
+        writer.write_all(&[*removed as u8])?;
+        writer.write_all(key)?;
+    }
+    let crc = writer.hasher.clone().finalize();
+    let mut file = writer.writer.into_inner().map_err(|e| e.into_error())?;
+    file.write_all(&crc.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, hint_file_path(dir, gen))?;
+    Ok(())
+}
+
+/// Reads the hint file of the log `gen`, which is `log_len` bytes long, and returns its
+/// `last_seq` and entries.
+///
+/// Returns `None` if there is no hint file, or if it is damaged or does not match the log,
+/// in which case the log has to be replayed instead.
+pub(crate) fn read_hint_file(
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+) -> Result<Option<(u64, Vec<HintEntry>)>> {
+    let path = hint_file_path(dir, gen);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = HashReader {
+        reader: BufReader::new(file),
+        hasher: Hasher::new(),
+    };
+
+    match read_entries(&mut reader, gen, log_len) {
+        Ok(Some(entries)) => {
+            let computed = reader.hasher.clone().finalize();
+            let mut crc = [0u8; 4];
+            let trailer_ok = reader.reader.read_exact(&mut crc).is_ok()
+                && u32::from_le_bytes(crc) == computed
+                && reader.reader.read(&mut [0u8])? == 0;
+            if trailer_ok {
+                return Ok(Some(entries));
+            }
+        }
+        Ok(None) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => return Err(e.into()),
+    }
+    warn!("Ignoring invalid hint file {}", path.display());
+    Ok(None)
+}
+
+/// Reads the `last_seq` and entries of a hint file, or returns `None` if it does not describe
+/// a log of `log_len` bytes.
+fn read_entries<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    log_len: u64,
+) -> io::Result<Option<(u64, Vec<HintEntry>)>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u64(reader)? != log_len {
+        return Ok(None);
+    }
+    let last_seq = read_u64(reader)?;
+    let count = read_u64(reader)?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut key_len = [0u8; 4];
+        reader.read_exact(&mut key_len)?;
+        let pos = read_u64(reader)?;
+        let len = read_u64(reader)?;
+        let seq = read_u64(reader)?;
+        let expires = Some(read_u64(reader)?).filter(|&expires| expires != 0);
+        let time = read_u64(reader)?;
+        let mut removed = [0u8];
+        reader.read_exact(&mut removed)?;
+        if pos.checked_add(len).is_none_or(|end| end > log_len) || removed[0] > 1 {
+            return Ok(None);
+        }
+        let mut key = Vec::new();
+        reader
+            .take(u32::from_le_bytes(key_len) as u64)
+            .read_to_end(&mut key)?;
+        let cmd_pos = CommandPos {
+            expires,
+            time,
+            ..(gen, pos..pos + len, seq).into()
+        };
+        let removed = removed[0] == 1;
+        entries.push((key, Version { cmd_pos, removed }));
+    }
+    Ok(Some((last_seq, entries)))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Writer computing the CRC32 of everything written through it.
+struct HashWriter<W: Write> {
+    writer: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reader computing the CRC32 of everything read through it.
+struct HashReader<R: Read> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
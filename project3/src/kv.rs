@@ -1,221 +1,1618 @@
-use crate::{KvsError, Result};
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::collections::{BTreeMap, HashMap};
+use crate::batch::WriteBatch;
+use crate::compaction::{remove_unfinished_compactions, BackgroundCompaction, Compactor};
+use crate::group_commit::{GroupCommit, SyncTicket};
+use crate::hint::{hint_file_path, read_hint_file};
+use crate::options::{Durability, KvStoreOptions, Retention};
+use crate::record::{now_millis, Codec, Command, Frame, Stamp, FORMAT_FILE};
+use crate::scan::{prefix_end, Scan};
+use crate::snapshot::{Snapshot, SnapshotRegistry};
+use crate::transaction::Transaction;
+use crate::{CompareAndSwapError, CompareAndSwapResult, KvsEngine, KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::ffi::OsStr;
-use std::fs::{create_dir_all, read, read_dir, read_to_string, File, OpenOptions};
+use std::fs::{create_dir_all, read_dir, File, OpenOptions, TryLockError};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
+use thread_local::ThreadLocal;
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Name of the file locked by the `KvStore` writing to a directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// Name of the directory a checkpoint is copied to by `KvStore::restore`, within the
+/// directory of the store it replaces.
+const RESTORE_DIR: &str = "restoring";
+
+/// Name of the file listing the files of the copy in `RESTORE_DIR`, written once the copy is
+/// complete, so that a restore stopped after that can be finished.
+const RESTORE_MANIFEST: &str = "MANIFEST";
 
 /// kv store: myDB
+///
+/// `KvStore` can be cloned cheaply and shared between threads. Reads run concurrently,
+/// each thread using its own log file readers, while writes are serialized by a single writer.
+///
+/// Compaction runs on a background thread, so writers only pay for switching to a new log.
+///
+/// A store holds an exclusive lock on its directory until the last clone is dropped, so that
+/// no other process writes to it meanwhile. A store opened read-only takes no lock.
+#[derive(Clone)]
 pub struct KvStore {
-    path: PathBuf,
-    // gen number to log file reader
-    readers: HashMap<u64, BuffReaderWithPos<File>>,
-    // writer of the current log file
-    writer: BuffWriterWithPos<File>,
-    index: BTreeMap<String, CommandPos>,
-    current_gen: u64,
-    uncompacted: u64,
+    // key to log position, shared by readers and the writer
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    access: Access,
+    // the logs live snapshots still read from
+    snapshots: Arc<SnapshotRegistry>,
+    // the old versions of the keys, unless the store keeps none
+    history: Option<Arc<History>>,
+}
+
+#[derive(Clone)]
+enum Access {
+    ReadWrite(WriteHalf),
+    // the end of the logs loaded so far, which `refresh` goes on from
+    ReadOnly(Arc<Mutex<LogTail>>),
+}
+
+/// What a writable `KvStore` needs on top of reading.
+#[derive(Clone)]
+struct WriteHalf {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    compactor: Arc<Compactor>,
+    // stops the background compaction thread when the last clone is dropped
+    compaction: Arc<BackgroundCompaction>,
+    // the `LOCK` file, declared last so that the lock outlives the compaction thread
+    _lock: Arc<File>,
 }
 
 impl KvStore {
     /// open directory [path]
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        create_dir_all(&path)?;
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// open directory [path] with the given options
+    ///
+    /// A store created before the data format was recorded in the directory is rewritten in
+    /// the current one when opened for writing, and read as it is when opened read-only.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another `KvStore` has the directory open for writing.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        let (codec, lock) = if options.read_only {
+            if path.join(RESTORE_DIR).join(RESTORE_MANIFEST).exists() {
+                return Err(KvsError::UnfinishedRestore);
+            }
+            (Codec::detect(&path, &sorted_gen_list(&path)?)?, None)
+        } else {
+            create_dir_all(&*path)?;
+            let lock = lock_dir(&path)?;
+            finish_restore(&path)?;
+            remove_unfinished_compactions(&path)?;
+            if Codec::detect(&path, &sorted_gen_list(&path)?)? == Codec::Legacy {
+                upgrade_legacy_store(&path)?;
+            }
+            (Codec::load(&path, &sorted_gen_list(&path)?)?, Some(lock))
+        };
         let gen_list = sorted_gen_list(&path)?;
-        let mut readers: HashMap<u64, BuffReaderWithPos<File>> = HashMap::new();
-        let mut uncompacted = 0u64;
-        let mut index: BTreeMap<String, CommandPos> = BTreeMap::new();
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            codec,
+            buffer_size: options.read_buffer_size,
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: Arc::new(ThreadLocal::new()),
+            reads: Arc::new(ReadTracker::default()),
+        };
+        let index = Arc::new(SkipMap::new());
+        let history = (options.retention != Retention::Off).then(Arc::default);
+        let mut tail = LogTail::default();
+        let loader = Loader::new(&index, history.as_deref());
+        let uncompacted = load_logs(&reader, &gen_list, &loader, &mut tail, options.read_only)?;
 
-        for &gen in &gen_list {
-            let mut reader = BuffReaderWithPos::new(File::open(log_file_path(&path, gen))?)?;
-            uncompacted += load_log_file(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
-        }
+        let snapshots = Arc::new(SnapshotRegistry::default());
+        let lock = match lock {
+            Some(lock) => lock,
+            None => {
+                return Ok(KvStore {
+                    index,
+                    reader,
+                    access: Access::ReadOnly(Arc::new(Mutex::new(tail))),
+                    snapshots,
+                    history,
+                })
+            }
+        };
 
+        let live = index.iter().map(|entry| entry.value().load().len).sum();
+        let expiring = index
+            .iter()
+            .filter_map(|entry| {
+                let cmd_pos = entry.value().load();
+                let expires = cmd_pos.expires?;
+                Some(Reverse((expires, cmd_pos.seq, entry.key().clone())))
+            })
+            .collect();
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let writer = new_log_file(&path, current_gen, options.write_buffer_size)?;
+        if options.durability != Durability::Buffered {
+            sync_dir(&path)?;
+        }
 
-        Ok(KvStore {
-            path,
-            readers,
+        let group_commit = match options.durability {
+            Durability::GroupCommit { interval } => Some(GroupCommit::new(
+                interval,
+                writer.writer.get_ref().try_clone()?,
+            )),
+            _ => None,
+        };
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            codec,
             writer,
-            index,
+            group_commit,
             current_gen,
             uncompacted,
+            live,
+            seq: tail.seq,
+            expiring,
+            collected_until: 0,
+            options,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            history: history.clone(),
+        }));
+
+        let compactor = Arc::new(Compactor {
+            path,
+            index: Arc::clone(&index),
+            history: history.clone(),
+            reader: reader.clone(),
+            writer: Arc::clone(&writer),
+            snapshots: Arc::clone(&snapshots),
+            lock: Mutex::new(()),
+        });
+
+        Ok(KvStore {
+            index,
+            reader,
+            access: Access::ReadWrite(WriteHalf {
+                writer,
+                compaction: Arc::new(BackgroundCompaction::start(Arc::clone(&compactor))?),
+                compactor,
+                _lock: Arc::new(lock),
+            }),
+            snapshots,
+            history,
         })
     }
 
-    /// set k/v pair
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    /// release reset entry
+    ///
+    /// Compacts every sealed log in the calling thread, waiting for a background
+    /// compaction in progress to finish first.
+    pub fn compact(&self) -> Result<()> {
+        self.write_half()?.compactor.compact()
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
+    /// open directory [path] read-only
+    ///
+    /// The store takes no lock and creates, writes or deletes nothing in the directory, so it
+    /// can read a store another process is writing to. Call `refresh` to see the writes made
+    /// after the store was opened.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// Loads the writes made to the directory since a read-only store was opened or last
+    /// refreshed.
+    ///
+    /// A writable store always sees its own writes, and refreshing it does nothing.
+    ///
+    /// A read of a read-only store can fail with a `NotFound` I/O error if the owner of the
+    /// store compacted away the log it points to. Refreshing fixes that.
+    pub fn refresh(&self) -> Result<()> {
+        let tail = match &self.access {
+            Access::ReadWrite(_) => return Ok(()),
+            Access::ReadOnly(tail) => tail,
+        };
+        let mut tail = tail.lock().unwrap();
+        let gen_list = sorted_gen_list(&self.reader.path)?;
+        let unseen = |gen: &u64| *gen < tail.gen && !tail.loaded.contains(gen);
+        if gen_list.iter().any(unseen) {
+            // a compaction renamed its log into place after the newer logs were loaded, as
+            // it does when refreshing between its start and its end: the older records go
+            // first, so every log is loaded again
+            self.reload(&gen_list, &mut tail)?;
+        } else {
+            let loader = Loader::new(&self.index, self.history.as_deref());
+            load_logs(&self.reader, &gen_list, &loader, &mut tail, true)?;
         }
+        tail.loaded.retain(|gen| gen_list.contains(gen));
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        // the keys still pointing at compacted logs were removed before the compaction, and
+        // the versions still there were dropped by it
+        let compacted = |cmd_pos: CommandPos| gen_list.binary_search(&cmd_pos.gen).is_err();
+        for entry in self.index.iter() {
+            if compacted(entry.value().load()) {
+                entry.remove();
+            }
+        }
+        if let Some(history) = &self.history {
+            history.lock().unwrap().retain(|_, versions| {
+                versions.retain(|version| !compacted(version.cmd_pos));
+                !versions.is_empty()
+            });
         }
+        let first_gen = gen_list.first().copied().unwrap_or(tail.gen);
+        self.reader.safe_point.store(first_gen, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Loads the logs of `gen_list` from the start into the index of a read-only store and
+    /// its history, and moves `tail` to their end.
+    ///
+    /// They are loaded apart and then swapped in, so that reads meanwhile still see the keys.
+    fn reload(&self, gen_list: &[u64], tail: &mut LogTail) -> Result<()> {
+        let index = Index::new();
+        let history = self.history.as_ref().map(|_| History::default());
+        let mut reloaded = LogTail::default();
+        let loader = Loader::new(&index, history.as_ref());
+        load_logs(&self.reader, gen_list, &loader, &mut reloaded, true)?;
 
+        for entry in index.iter() {
+            update_index(&self.index, entry.key(), entry.value().load());
+        }
+        for entry in self.index.iter() {
+            if !index.contains_key(entry.key()) {
+                entry.remove();
+            }
+        }
+        if let (Some(kept), Some(history)) = (&self.history, history) {
+            *kept.lock().unwrap() = history.into_inner().unwrap();
+        }
+        *tail = reloaded;
         Ok(())
     }
 
-    /// retrieve value from key
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Can not find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let content = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(content)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    /// Returns the key/value pairs with keys in `range`, in key order.
+    ///
+    /// Values are read from the logs as the iterator advances, and `rev` walks the range
+    /// from its end. Writes made during the scan may or may not be seen.
+    ///
+    /// Keys are ordered by their bytes. The pairs are read as strings, or as bytes after
+    /// `Scan::bytes`.
+    pub fn scan<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Scan<'_> {
+        let owned = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+        let now = now_millis();
+        let entries = self
+            .index
+            .range((owned(range.start_bound()), owned(range.end_bound())))
+            .filter_map(move |entry| {
+                let cmd_pos = entry.value().load();
+                (!cmd_pos.is_expired(now)).then(|| (entry.key().clone(), cmd_pos))
+            });
+        Scan::new(Box::new(entries), &self.reader)
+    }
+
+    /// Returns a read-only view of the store as it is now, which later writes leave as is.
+    ///
+    /// The snapshot copies the positions of the values, not the values, so taking it walks
+    /// the keys while writes wait. The logs it reads from stay on disk until it is dropped,
+    /// even once compacted, and the directory stays locked until then. A snapshot of a
+    /// read-only store cannot keep the owner of the store from deleting them, see `refresh`.
+    pub fn snapshot(&self) -> Snapshot {
+        // no write may happen while the positions are copied
+        let _writer = match &self.access {
+            Access::ReadWrite(write) => Some(write.writer.lock().unwrap()),
+            Access::ReadOnly(_) => None,
+        };
+        let _tail = match &self.access {
+            Access::ReadWrite(_) => None,
+            Access::ReadOnly(tail) => Some(tail.lock().unwrap()),
+        };
+        let now = now_millis();
+        let entries: Vec<_> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .collect();
+        let reader = KvStoreReader {
+            path: Arc::clone(&self.reader.path),
+            codec: self.reader.codec,
+            buffer_size: self.reader.buffer_size,
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: Arc::new(ThreadLocal::new()),
+            reads: Arc::new(ReadTracker::default()),
+        };
+        let lock = match &self.access {
+            Access::ReadWrite(write) => Some(Arc::clone(&write._lock)),
+            Access::ReadOnly(_) => None,
+        };
+        Snapshot::new(entries, reader, Arc::clone(&self.snapshots), lock)
+    }
+
+    /// Writes a copy of the store as it is now to the directory `dest`, which can then be
+    /// opened as a store of its own.
+    ///
+    /// The sealed logs are hard-linked into `dest` where the filesystem allows it, and copied
+    /// otherwise. The active log is copied up to the end of the last write, while later
+    /// writes go on. Compactions wait for the checkpoint to finish.
+    ///
+    /// A read-only store copies the logs it loaded, and fails with a `NotFound` I/O error if
+    /// the owner of the store compacted them away meanwhile, see `refresh`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StoreExists` if `dest` holds a store already.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_dir_all(dest)?;
+        if dest.join(FORMAT_FILE).exists() || !sorted_gen_list(dest)?.is_empty() {
+            return Err(KvsError::StoreExists);
+        }
+        let (_compaction, gen, len) = match &self.access {
+            Access::ReadWrite(write) => {
+                // no compaction may delete the sealed logs until they are linked
+                let compaction = write.compactor.lock.lock().unwrap();
+                let mut writer = write.writer.lock().unwrap();
+                writer.writer.flush()?;
+                (Some(compaction), writer.current_gen, writer.writer.pos)
             }
+            Access::ReadOnly(tail) => {
+                let tail = tail.lock().unwrap();
+                (None, tail.gen, tail.pos)
+            }
+        };
+        copy_logs(&self.reader.path, dest, gen, len)?;
+        self.reader.codec.save(dest)?;
+        sync_dir(dest)
+    }
+
+    /// Replaces the store in the directory `dest`, if any, with the checkpoint in `src`, see
+    /// `checkpoint`.
+    ///
+    /// The checkpoint is copied to a directory within `dest` first. Only once the copy is
+    /// complete are the copied files moved in place of those of the old store, which are
+    /// deleted. If that is cut short by a crash, the next writable open or restore of `dest`
+    /// finishes it, and a read-only open fails until then.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if a store has `dest` open, and a `NotFound` I/O error
+    /// if `src` holds no store. `dest` is left as is then, as well as when the copy fails.
+    pub fn restore(src: impl Into<PathBuf>, dest: impl AsRef<Path>) -> Result<()> {
+        let src = src.into();
+        // a store without the `format` file may use the legacy codec
+        let has_logs = src.is_dir() && !sorted_gen_list(&src)?.is_empty();
+        if !src.join(FORMAT_FILE).exists() && !has_logs {
+            let message = format!("No store in {}", src.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+        }
+        let dest = dest.as_ref();
+        create_dir_all(dest)?;
+        let _lock = lock_dir(dest)?;
+        let checkpoint = KvStore::open_read_only(src)?;
+
+        // a restore which stopped once its copy was complete is finished first, while an
+        // incomplete copy is of no use
+        finish_restore(dest)?;
+        let copy = dest.join(RESTORE_DIR);
+        if copy.is_dir() {
+            fs::remove_dir_all(&copy)?;
+        }
+        if let Err(e) = checkpoint.checkpoint(&copy) {
+            if copy.is_dir() {
+                fs::remove_dir_all(&copy)?;
+            }
+            return Err(e);
+        }
+        write_restore_manifest(&copy)?;
+        finish_restore(dest)
+    }
+
+    /// Returns every key/value pair in key order, see `scan`.
+    pub fn iter(&self) -> Scan<'_> {
+        self.scan::<&[u8]>(..)
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order, see `scan`.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        let prefix = prefix.as_ref();
+        self.scan((Bound::Included(prefix.to_vec()), prefix_end(prefix)))
+    }
+
+    /// Returns the number of keys starting with `prefix`.
+    ///
+    /// It walks the keys, but reads no value.
+    pub fn count_prefix(&self, prefix: impl AsRef<[u8]>) -> usize {
+        self.scan_prefix(prefix).bytes().keys().count()
+    }
+
+    /// Returns the number of keys in the store.
+    ///
+    /// It walks the keys, to leave out the expired ones.
+    pub fn len(&self) -> usize {
+        self.iter().keys().count()
+    }
+
+    /// Returns whether the store has no key.
+    pub fn is_empty(&self) -> bool {
+        self.iter().keys().next().is_none()
+    }
+
+    fn write_half(&self) -> Result<&WriteHalf> {
+        match &self.access {
+            Access::ReadWrite(write) => Ok(write),
+            Access::ReadOnly(_) => Err(KvsError::ReadOnly),
+        }
+    }
+}
+
+impl KvStore {
+    /// set k/v pair of bytes
+    pub fn set_bytes(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.write_with(|writer| writer.set(key.as_ref().to_vec(), value.as_ref().to_vec(), None))
+    }
+
+    /// retrieve value of bytes from key
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_with_seq(key.as_ref())?.map(|(value, _)| value))
+    }
+
+    /// Returns the value of `key` with the sequence number of the command which set it.
+    pub(crate) fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        // keeps compaction from deleting the log the index points to until the read is done
+        let _read = self.reader.reads.enter();
+        if let Some(cmd_pos) = live_pos(&self.index, key, now_millis()) {
+            Ok(Some((self.reader.read_value(cmd_pos)?, cmd_pos.seq)))
         } else {
             Ok(None)
         }
     }
 
+    /// Sets `key` to `value` for `ttl`, after which the key reads as missing.
+    ///
+    /// The expired value counts as garbage, which the next compaction drops, unless the
+    /// `Retention` of the store keeps it as an old version.
+    pub fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires = now_millis().saturating_add(ttl);
+        self.write_with(|writer| {
+            writer.set(
+                key.as_ref().to_vec(),
+                value.as_ref().to_vec(),
+                Some(expires),
+            )
+        })
+    }
+
+    /// Returns the time left before `key` expires, or `None` if it does not expire.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key is missing or expired.
+    pub fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match live_pos(&self.index, key.as_ref(), now) {
+            Some(cmd_pos) => Ok(cmd_pos
+                .expires
+                .map(|expires| Duration::from_millis(expires - now))),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Returns the sequence number of the last command written to the store, or loaded by a
+    /// read-only store.
+    ///
+    /// Every command gets the next number, so that `get_at` can read the store as it was
+    /// after any of them.
+    pub fn last_seq(&self) -> u64 {
+        match &self.access {
+            Access::ReadWrite(write) => write.writer.lock().unwrap().seq,
+            Access::ReadOnly(tail) => tail.lock().unwrap().seq,
+        }
+    }
+
+    /// Returns the value `key` had after the command numbered `seq`, as a string.
+    ///
+    /// It returns `KvsError::Utf8` if the value is not UTF-8.
+    pub fn get_at(&self, key: impl AsRef<[u8]>, seq: u64) -> Result<Option<String>> {
+        match self.get_at_bytes(key, seq)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value `key` had after the command numbered `seq`, see `last_seq`.
+    ///
+    /// Only the versions in `history` can be read: before the oldest of them, the key reads
+    /// as missing. Expiries are not taken into account.
+    pub fn get_at_bytes(&self, key: impl AsRef<[u8]>, seq: u64) -> Result<Option<Vec<u8>>> {
+        // taken before the positions, see `get_bytes`
+        let _read = self.reader.reads.enter();
+        let version = self
+            .versions(key.as_ref())
+            .into_iter()
+            .rev()
+            .find(|version| version.cmd_pos.seq <= seq);
+        match version {
+            Some(version) if !version.removed => Ok(Some(self.reader.read_value(version.cmd_pos)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the versions of `key`, oldest first: the old versions kept by the `Retention`
+    /// of the store, then the current value if the key has one.
+    ///
+    /// Expired values are listed all the same.
+    pub fn history(&self, key: impl AsRef<[u8]>) -> Result<Vec<KeyVersion>> {
+        let _read = self.reader.reads.enter();
+        self.versions(key.as_ref())
+            .into_iter()
+            .map(|Version { cmd_pos, removed }| {
+                let value = if removed {
+                    None
+                } else {
+                    Some(self.reader.read_value(cmd_pos)?)
+                };
+                Ok(KeyVersion {
+                    seq: cmd_pos.seq,
+                    time: UNIX_EPOCH + Duration::from_millis(cmd_pos.time),
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the old versions of `key` and its current one, oldest first.
+    fn versions(&self, key: &[u8]) -> Vec<Version> {
+        // the writer updates the index with the history locked
+        let history = self.history.as_ref().map(|history| history.lock().unwrap());
+        let mut versions = history
+            .as_ref()
+            .and_then(|history| history.get(key).cloned())
+            .unwrap_or_default();
+        if let Some(entry) = self.index.get(key) {
+            versions.push(Version {
+                cmd_pos: entry.value().load(),
+                removed: false,
+            });
+        }
+        versions
+    }
+
+    /// remove k/v pair of bytes
+    pub fn remove_bytes(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.write_with(|writer| writer.remove(key.as_ref().to_vec()))
+    }
+
+    /// Applies every command of `batch`, or none of them.
+    ///
+    /// The batch is written as a single record, so a crash cannot leave part of it in the
+    /// store. Concurrent readers may still see part of it until it is applied whole.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which does not exist at
+    /// that point, and then writes nothing.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_with(|writer| writer.write_batch(batch.cmds))
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, if its value is `expected`,
+    /// where `None` stands for a missing key.
+    ///
+    /// The check and the write happen together, with no other write in between. If the
+    /// value is not the expected one, nothing is written and the inner result is an error
+    /// holding the current value.
+    pub fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> CompareAndSwapResult {
+        let key = key.as_ref();
+        let mut mismatch = None;
+        self.write_with(|writer| {
+            let current = self.get_bytes(key)?;
+            if current.as_deref() != expected {
+                mismatch = Some(CompareAndSwapError { current });
+                return Ok(None);
+            }
+            match (new, current) {
+                (Some(new), _) => writer.set(key.to_vec(), new.to_vec(), None),
+                (None, Some(_)) => writer.remove(key.to_vec()),
+                (None, None) => Ok(None),
+            }
+        })?;
+        Ok(match mismatch {
+            Some(mismatch) => Err(mismatch),
+            None => Ok(()),
+        })
+    }
+
+    /// Sets `key` to `value` unless the key exists, see `compare_and_swap`.
+    pub fn set_if_absent(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> CompareAndSwapResult {
+        self.compare_and_swap(key, None, Some(value.as_ref()))
+    }
+
+    /// Removes `key` if its value is `expected`, see `compare_and_swap`.
+    pub fn remove_if_equals(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: impl AsRef<[u8]>,
+    ) -> CompareAndSwapResult {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+
+    /// Runs `f` in a transaction, then commits the writes it made and returns its result.
+    ///
+    /// The transaction takes no lock while `f` runs. Its writes are committed as a single
+    /// batch record, and only if no key it read was written since.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` if a key read by the transaction was written by
+    /// someone else before the commit, and then writes nothing: the transaction can be run
+    /// again. An error returned by `f` aborts the transaction as well.
+    pub fn transaction<T>(&self, f: impl FnOnce(&mut Transaction<'_>) -> Result<T>) -> Result<T> {
+        let mut txn = Transaction::new(self);
+        let result = f(&mut txn)?;
+        let Transaction { reads, writes, .. } = txn;
+        self.write_with(|writer| writer.commit_transaction(reads, writes))?;
+        Ok(result)
+    }
+
+    /// Runs `write` with the writer, then triggers a compaction if it left too much garbage
+    /// and waits for the write to be durable.
+    fn write_with(
+        &self,
+        write: impl FnOnce(&mut KvStoreWriter) -> Result<Option<SyncTicket>>,
+    ) -> Result<()> {
+        let write_half = self.write_half()?;
+        let ticket = {
+            let mut writer = write_half.writer.lock().unwrap();
+            let ticket = write(&mut writer)?;
+            if writer.needs_compaction() {
+                write_half.compaction.trigger();
+            }
+            ticket
+        };
+        wait_for_sync(ticket)
+    }
+}
+
+/// The string API, which wraps the byte API of `KvStore`.
+impl KvsEngine for KvStore {
+    /// set k/v pair
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write_with(|writer| writer.set(key.into_bytes(), value.into_bytes(), None))
+    }
+
+    /// retrieve value from key
+    ///
+    /// It returns `KvsError::Utf8` if the value is not UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
     /// remove k/v pair
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove { key };
-            serde_json::to_writer(&mut self.writer, &cmd)?;
+    fn remove(&self, key: String) -> Result<()> {
+        self.write_with(|writer| writer.remove(key.into_bytes()))
+    }
+}
 
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("Key does not exist");
-                self.uncompacted += old_cmd.len;
+/// A version of a key, see `KvStore::history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// The sequence number of the command which wrote it.
+    pub seq: u64,
+    /// When the command was written. Commands recorded without their time count as written
+    /// when the store loaded them.
+    pub time: SystemTime,
+    /// The value, or `None` if the command removed the key.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Waits for the group commit of a write, if it has to.
+fn wait_for_sync(ticket: Option<SyncTicket>) -> Result<()> {
+    match ticket {
+        Some(ticket) => ticket.wait(),
+        None => Ok(()),
+    }
+}
+
+/// Key to the position of its latest command in the log.
+///
+/// `CommandPos` is too large for a native atomic, so `AtomicCell` guards each position with
+/// one of a global set of seqlocks: a read is optimistic, and only takes the lock when it
+/// races with a write under the same lock.
+pub(crate) type Index = SkipMap<Vec<u8>, AtomicCell<CommandPos>>;
+
+/// Key to its old versions, oldest first, as kept by `Retention`.
+pub(crate) type History = Mutex<BTreeMap<Vec<u8>, Vec<Version>>>;
+
+/// A version of a key in the log: the record of a command, and whether it removed the key.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Version {
+    pub(crate) cmd_pos: CommandPos,
+    pub(crate) removed: bool,
+}
+
+/// Gen number to log file reader.
+type Readers = BTreeMap<u64, BuffReaderWithPos<File>>;
+
+/// Read side of `KvStore`.
+///
+/// Each thread has its own log file readers, opened lazily, so reads never wait on each other.
+#[derive(Clone)]
+pub(crate) struct KvStoreReader {
+    pub(crate) path: Arc<PathBuf>,
+    codec: Codec,
+    buffer_size: usize,
+    // generation of the latest compaction file, readers of older generations are stale
+    pub(crate) safe_point: Arc<AtomicU64>,
+    // per-thread map of gen number to log file reader
+    pub(crate) readers: Arc<ThreadLocal<RefCell<Readers>>>,
+    // reads in progress, which compaction waits for before deleting stale logs
+    pub(crate) reads: Arc<ReadTracker>,
+}
+
+impl KvStoreReader {
+    /// Close file handles with generation number less than safe_point.
+    ///
+    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than safe_point.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    fn close_stale_handles(&self, readers: &mut Readers) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        while let Some(&first_gen) = readers.keys().next() {
+            if safe_point <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    pub(crate) fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BuffReaderWithPos<File>>) -> Result<R>,
+    {
+        let mut readers = self.readers.get_or_default().borrow_mut();
+        self.close_stale_handles(&mut readers);
+        let reader = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(BuffReaderWithPos::with_capacity(
+                self.buffer_size,
+                open_log_file(&self.path, cmd_pos.gen)?,
+            )?),
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd_reader = reader.take(cmd_pos.len);
+        f(cmd_reader)
+    }
+
+    /// Read the value of the `Command::Set` at the given `CommandPos`.
+    pub(crate) fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(value),
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Read the log file at the given `CommandPos` and decode it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            match self
+                .codec
+                .read_record(&mut cmd_reader, cmd_pos.gen, cmd_pos.pos)?
+            {
+                Frame::Record(cmd, _, len) if len == cmd_pos.len => Ok(cmd),
+                _ => Err(KvsError::Corruption {
+                    gen: cmd_pos.gen,
+                    offset: cmd_pos.pos,
+                }),
+            }
+        })
+    }
+}
+
+/// Tracks the reads in progress, so that compaction can wait for every read that may
+/// still use a log it is about to delete.
+///
+/// Reads are counted in one of two slots, picked by the parity of `epoch`. Compaction
+/// moves to the next epoch and waits for the slot of the previous one to drain, while
+/// new reads go to the other slot.
+#[derive(Default)]
+pub(crate) struct ReadTracker {
+    epoch: AtomicUsize,
+    active: [AtomicUsize; 2],
+}
+
+impl ReadTracker {
+    /// Registers a read, which lasts until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> ReadGuard<'_> {
+        loop {
+            let slot = self.epoch.load(Ordering::SeqCst) % 2;
+            self.active[slot].fetch_add(1, Ordering::SeqCst);
+            // the epoch moved on in between, the compaction may not have seen this read
+            if self.epoch.load(Ordering::SeqCst) % 2 == slot {
+                return ReadGuard {
+                    tracker: self,
+                    slot,
+                };
             }
-            Ok(())
+            self.active[slot].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Waits until every read that started before this call has finished.
+    pub(crate) fn wait_for_readers(&self) {
+        let slot = self.epoch.fetch_add(1, Ordering::SeqCst) % 2;
+        while self.active[slot].load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+pub(crate) struct ReadGuard<'a> {
+    tracker: &'a ReadTracker,
+    slot: usize,
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.active[self.slot].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct KvStoreWriter {
+    codec: Codec,
+    // writer of the current log file
+    writer: BuffWriterWithPos<File>,
+    // set with `Durability::GroupCommit`
+    group_commit: Option<Arc<GroupCommit>>,
+    current_gen: u64,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
+    uncompacted: u64,
+    // the number of bytes of the commands the index points to
+    live: u64,
+    // sequence number of the last command written
+    pub(crate) seq: u64,
+    // expiry, sequence number and key of the values which expire, soonest first
+    expiring: BinaryHeap<Reverse<(u64, u64, Vec<u8>)>>,
+    // the values expired by then are already counted as garbage
+    collected_until: u64,
+    pub(crate) options: KvStoreOptions,
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+    history: Option<Arc<History>>,
+}
+
+impl KvStoreWriter {
+    /// Returns the ticket to wait on for the write to be durable, with group commit.
+    fn set(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    ) -> Result<Option<SyncTicket>> {
+        let cmd = Command::Set {
+            key,
+            value,
+            expires,
+        };
+        let pos = self.writer.pos;
+        let seq = self.seq + 1;
+        let time = now_millis();
+        self.codec.write_record(&mut self.writer, &cmd, seq, time)?;
+        self.seq = seq;
+        let ticket = self.commit()?;
+
+        let cmd_pos = (self.current_gen, pos..self.writer.pos, seq).into();
+        self.apply(cmd, CommandPos { time, ..cmd_pos });
+        self.roll_if_full()?;
+        Ok(ticket)
+    }
+
+    /// Returns the ticket to wait on for the write to be durable, with group commit.
+    fn remove(&mut self, key: Vec<u8>) -> Result<Option<SyncTicket>> {
+        if live_pos(&self.index, &key, now_millis()).is_some() {
+            let cmd = Command::Remove { key };
+            let pos = self.writer.pos;
+            let seq = self.seq + 1;
+            let time = now_millis();
+            self.codec.write_record(&mut self.writer, &cmd, seq, time)?;
+            self.seq = seq;
+            let ticket = self.commit()?;
+
+            let cmd_pos = (self.current_gen, pos..self.writer.pos, seq).into();
+            self.apply(cmd, CommandPos { time, ..cmd_pos });
+            self.roll_if_full()?;
+            Ok(ticket)
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    /// release reset entry
-    pub fn compact(&mut self) -> Result<()> {
-        self.writer = self.new_log_file(self.current_gen + 2)?;
-        let compact_gen = self.current_gen + 1;
-        let mut compact_writer = self.new_log_file(compact_gen)?;
-        self.current_gen += 2;
+    /// Write `cmds` as a single batch record.
+    ///
+    /// A remove of a key which is missing by then fails the whole batch, before anything is
+    /// written.
+    ///
+    /// Returns the ticket to wait on for the write to be durable, with group commit.
+    fn write_batch(&mut self, cmds: Vec<Command>) -> Result<Option<SyncTicket>> {
+        // whether each key touched by the batch exists after its commands so far
+        let now = now_millis();
+        let mut exists = HashMap::new();
+        for cmd in &cmds {
+            match cmd {
+                Command::Set { key, .. } => {
+                    exists.insert(key.as_slice(), true);
+                }
+                Command::Remove { key } => {
+                    let existed = match exists.get(key.as_slice()) {
+                        Some(&existed) => existed,
+                        None => live_pos(&self.index, key, now).is_some(),
+                    };
+                    if !existed {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key.as_slice(), false);
+                }
+            }
+        }
+
+        let pos = self.writer.pos;
+        let first_seq = self.seq + 1;
+        let ranges = self
+            .codec
+            .write_batch(&mut self.writer, &cmds, first_seq, now)?;
+        self.seq += cmds.len() as u64;
+        let ticket = self.commit()?;
+
+        for ((seq, cmd), range) in (first_seq..).zip(cmds).zip(ranges) {
+            let cmd_pos = (self.current_gen, pos + range.start..pos + range.end, seq).into();
+            self.apply(
+                cmd,
+                CommandPos {
+                    time: now,
+                    ..cmd_pos
+                },
+            );
+        }
+        self.roll_if_full()?;
+        Ok(ticket)
+    }
 
-        // copy from old file to new file
-        let mut compact_pos = 0u64;
-        for cmd_pos in self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("log file reader does not exist");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    /// Write the pending `writes` of a transaction as a single batch record, if the keys it
+    /// read still have the versions in `reads`.
+    ///
+    /// Returns the ticket to wait on for the write to be durable, with group commit.
+    fn commit_transaction(
+        &mut self,
+        reads: HashMap<Vec<u8>, Option<u64>>,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Option<SyncTicket>> {
+        let now = now_millis();
+        for (key, seq) in reads {
+            let current = live_pos(&self.index, &key, now).map(|cmd_pos| cmd_pos.seq);
+            if current != seq {
+                return Err(KvsError::Conflict);
             }
-            let mut reader_take = reader.take(cmd_pos.len);
-            let len = io::copy(&mut reader_take, &mut compact_writer)?;
-            *cmd_pos = (compact_gen, (compact_pos..compact_pos + len)).into();
-            compact_pos += len;
-        }
-        compact_writer.flush()?;
-
-        let stale_gen: Vec<u64> = self
-            .readers
-            .keys()
-            .filter(|it| **it < compact_gen)
-            .cloned()
+        }
+
+        let cmds: Vec<_> = writes
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Some(value) => Some(Command::Set {
+                    key,
+                    value,
+                    expires: None,
+                }),
+                // a key set and removed again by the transaction may not be in the store
+                None if live_pos(&self.index, &key, now).is_some() => Some(Command::Remove { key }),
+                None => None,
+            })
             .collect();
-        // remove stale files and entry
-        for gen in stale_gen {
-            self.readers.remove(&gen);
-            fs::remove_file(log_file_path(&self.path, gen))?;
+        if cmds.is_empty() {
+            return Ok(None);
         }
+        self.write_batch(cmds)
+    }
 
-        self.uncompacted = 0;
+    /// Apply a command written at `cmd_pos` to the index.
+    fn apply(&mut self, cmd: Command, cmd_pos: CommandPos) {
+        match &cmd {
+            // expired already, like the values `collect_expired` went through
+            Command::Set {
+                expires: Some(expires),
+                ..
+            } if *expires <= self.collected_until => self.uncompacted += cmd_pos.len,
+            Command::Set { key, expires, .. } => {
+                self.live += cmd_pos.len;
+                if let Some(expires) = *expires {
+                    self.expiring
+                        .push(Reverse((expires, cmd_pos.seq, key.clone())));
+                }
+            }
+            Command::Remove { .. } => {}
+        }
+        // readers of the history expect the index to move on with it
+        let mut history = self.history.as_ref().map(|history| history.lock().unwrap());
+        let old_cmd = apply_command(&self.index, &cmd, cmd_pos);
+        if let Some(history) = &mut history {
+            let (key, removal) = match &cmd {
+                Command::Set { key, .. } => (key, None),
+                Command::Remove { key } => (key, Some(cmd_pos)),
+            };
+            keep_history(history, key, old_cmd, removal);
+        }
+        if let Some(old_cmd) = old_cmd {
+            if !old_cmd.is_expired(self.collected_until) {
+                self.uncompacted += old_cmd.len;
+                self.live -= old_cmd.len;
+            }
+        }
+    }
+
+    /// Count the values expired at `now` as garbage, unless they were overwritten already.
+    fn collect_expired(&mut self, now: u64) {
+        while let Some(Reverse((expires, _, _))) = self.expiring.peek() {
+            if *expires > now {
+                break;
+            }
+            let Reverse((_, seq, key)) = self.expiring.pop().unwrap();
+            if let Some(entry) = self.index.get(&key) {
+                let cmd_pos = entry.value().load();
+                if cmd_pos.seq == seq {
+                    self.live -= cmd_pos.len;
+                    self.uncompacted += cmd_pos.len;
+                }
+            }
+        }
+        self.collected_until = self.collected_until.max(now);
+    }
+
+    /// Make the records written so far as durable as the options ask for.
+    fn commit(&mut self) -> Result<Option<SyncTicket>> {
+        self.writer.flush()?;
+        if let Some(group_commit) = &self.group_commit {
+            return Ok(Some(group_commit.register()));
+        }
+        if self.options.durability == Durability::Sync {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        Ok(None)
+    }
+
+    /// Whether the garbage in the logs, expired values included, calls for a compaction.
+    pub(crate) fn needs_compaction(&mut self) -> bool {
+        self.collect_expired(now_millis());
+        self.options
+            .compaction
+            .should_compact(self.uncompacted, self.live)
+    }
+
+    /// Continue writing to a new log once the current one reaches the maximum log size.
+    ///
+    /// The full log is sealed as is, and compacted along with the others.
+    fn roll_if_full(&mut self) -> Result<()> {
+        if self.writer.pos >= self.options.max_log_size {
+            self.current_gen += 1;
+            self.switch_log()?;
+        }
+        Ok(())
+    }
+
+    /// Continue writing to the log of `current_gen`.
+    fn switch_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        // writes waiting for a group commit may be in the old log, which the group
+        // will not sync anymore
+        if self.options.durability != Durability::Buffered {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        self.writer = new_log_file(&self.path, self.current_gen, self.options.write_buffer_size)?;
+        if self.options.durability != Durability::Buffered {
+            // makes the new log itself durable
+            sync_dir(&self.path)?;
+        }
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.switch_file(self.writer.writer.get_ref().try_clone()?);
+        }
         Ok(())
     }
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
+    /// Seal the current log and continue writing to a new one.
     ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BuffWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// Returns the generation reserved for the compaction of the sealed logs, which sorts
+    /// between them and the new log. The values expired at `now` are dropped with the
+    /// garbage.
+    pub(crate) fn seal(&mut self, now: u64) -> Result<u64> {
+        let compact_gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.switch_log()?;
+        // the garbage in the sealed logs is about to be dropped
+        self.collect_expired(now);
+        self.uncompacted = 0;
+        Ok(compact_gen)
+    }
+}
+
+/// Point `key` at `cmd_pos` in the index and return the position it replaced.
+///
+/// An existing entry is updated in place: replacing it in the skip list would
+/// briefly hide the key from concurrent readers.
+fn update_index(index: &Index, key: &[u8], cmd_pos: CommandPos) -> Option<CommandPos> {
+    match index.get(key) {
+        Some(entry) => Some(entry.value().swap(cmd_pos)),
+        None => {
+            index.insert(key.to_vec(), AtomicCell::new(cmd_pos));
+            None
+        }
+    }
+}
+
+/// Returns the position of the value of `key`, unless it is missing or expired at `now`.
+fn live_pos(index: &Index, key: &[u8], now: u64) -> Option<CommandPos> {
+    let cmd_pos = index.get(key)?.value().load();
+    (!cmd_pos.is_expired(now)).then_some(cmd_pos)
+}
+
+/// Apply `cmd`, whose record is at `cmd_pos`, to the index and return the position of the
+/// record it made stale.
+fn apply_command(index: &Index, cmd: &Command, cmd_pos: CommandPos) -> Option<CommandPos> {
+    match cmd {
+        Command::Set { key, expires, .. } => update_index(
+            index,
+            key,
+            CommandPos {
+                expires: *expires,
+                ..cmd_pos
+            },
+        ),
+        Command::Remove { key } => index.remove(key).map(|entry| entry.value().load()),
+    }
+}
+
+/// Record in `history` the versions of `key` a command superseded: `old`, the position it
+/// replaced in the index, and the command itself if it is the `removal` of the key.
+fn keep_history(
+    history: &mut BTreeMap<Vec<u8>, Vec<Version>>,
+    key: &[u8],
+    old: Option<CommandPos>,
+    removal: Option<CommandPos>,
+) {
+    // a new key has no old version yet
+    if old.is_none() && !history.contains_key(key) {
+        return;
     }
+    let versions = history.entry(key.to_vec()).or_default();
+    versions.extend(old.map(|cmd_pos| Version {
+        cmd_pos,
+        removed: false,
+    }));
+    versions.extend(removal.map(|cmd_pos| Version {
+        cmd_pos,
+        removed: true,
+    }));
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Create a new log file with given generation number.
 ///
 /// Returns the writer to the log.
-fn new_log_file(
-    path: &PathBuf,
-    gen: u64,
-    readers: &mut HashMap<u64, BuffReaderWithPos<File>>,
-) -> Result<BuffWriterWithPos<File>> {
+fn new_log_file(path: &Path, gen: u64, buffer_size: usize) -> Result<BuffWriterWithPos<File>> {
     let path = log_file_path(path, gen);
-    let writer = BuffWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    );
+    BuffWriterWithPos::with_capacity(
+        buffer_size,
+        OpenOptions::new().create(true).append(true).open(path)?,
+    )
+}
 
-    readers.insert(gen, BuffReaderWithPos::new(File::open(path)?)?);
-    writer
+/// Take the exclusive lock of the directory `path`.
+///
+/// The lock is released when the returned file is closed, including by the OS if the
+/// process dies.
+fn lock_dir(path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK_FILE))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
 }
 
-/// load single log file, store values location in index map and return uncompatted bytes
-fn load_log_file(
+/// Sync the directory `path`, so that the files created in it survive a crash.
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// The end of the logs loaded into an index.
+#[derive(Default)]
+struct LogTail {
     gen: u64,
-    reader: &mut BuffReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
+    pos: u64,
+    // sequence number of the last command loaded
+    seq: u64,
+    // the logs loaded so far, which a compaction can add an older one to
+    loaded: Vec<u64>,
+}
+
+impl LogTail {
+    /// Moves the tail to `pos` in the log `gen`.
+    fn advance(&mut self, gen: u64, pos: u64) {
+        if !self.loaded.contains(&gen) {
+            self.loaded.push(gen);
+        }
+        self.gen = gen;
+        self.pos = pos;
+    }
+}
+
+/// Load the logs of `gen_list` with `loader` and return the uncompacted bytes.
+///
+/// Loading goes on from `tail`, which is moved to the end of the loaded logs. A log with a
+/// hint file is loaded from it instead. Commands get the sequence numbers of their records,
+/// or else are numbered in the order they are loaded.
+///
+/// A torn record at the end of the newest log, after a complete one, is what a crash in the
+/// middle of a write leaves behind, so it is truncated away. The codec only reports a record
+/// as torn if its header is cut short, its payload runs past the end of the log, or the log
+/// goes on with nothing but zeros. A log torn in its first record is reported as corruption.
+/// If `read_only`, a torn record is left as is: the owner of the store may still be writing
+/// it. Logs deleted by the owner meanwhile are skipped as well.
+fn load_logs(
+    reader: &KvStoreReader,
+    gen_list: &[u64],
+    loader: &Loader,
+    tail: &mut LogTail,
+    read_only: bool,
 ) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let (path, codec) = (reader.path.as_path(), reader.codec);
     let mut uncompacted = 0u64;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+    let first_gen = tail.gen;
+    for &gen in gen_list.iter().filter(|&&gen| gen >= first_gen) {
+        let file = match File::open(log_file_path(path, gen)) {
+            Ok(file) => file,
+            Err(e) if read_only && e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let log_len = file.metadata()?.len();
+        let start = if gen == tail.gen { tail.pos } else { 0 };
+
+        // a compacted generation has a hint file, which spares replaying its log
+        if start == 0 {
+            if let Some((last_seq, entries)) = read_hint_file(path, gen, log_len)? {
+                for (key, mut version) in entries {
+                    let seq = Some(version.cmd_pos.seq);
+                    version.cmd_pos.seq = loaded_seq(seq, &mut tail.seq);
+                    uncompacted += loader.load(key, version);
                 }
+                // the last commands may have been dropped by the compaction
+                tail.seq = tail.seq.max(last_seq);
+                tail.advance(gen, log_len);
+                continue;
+            }
+        }
+
+        let mut reader = BuffReaderWithPos::with_capacity(reader.buffer_size, file)?;
+        // only the newest log can have been cut short by a crash, the others are sealed
+        let active = Some(&gen) == gen_list.last();
+        let (garbage, end) = load_log_file(
+            codec,
+            gen,
+            &mut reader,
+            start,
+            loader,
+            active,
+            &mut tail.seq,
+        )?;
+        uncompacted += garbage;
+        if end < log_len && !read_only {
+            // a crash can only have cut short a record written after others that made it
+            if end == start {
+                return Err(KvsError::Corruption { gen, offset: end });
             }
+            warn!(
+                "Truncating torn record at the end of {}.log at offset {}",
+                gen, end
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(log_file_path(path, gen))?
+                .set_len(end)?;
+        }
+        tail.advance(gen, end);
+    }
+    Ok(uncompacted)
+}
 
-            Command::Remove { key, .. } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+/// load single log file from `start`, store values location with `loader` and return
+/// uncompatted bytes and where the loaded records end
+///
+/// A torn record ends the `active` log early. Anywhere else it is reported as corruption.
+///
+/// `last_seq` is the sequence number of the last command loaded, and moves on with the log.
+fn load_log_file(
+    codec: Codec,
+    gen: u64,
+    reader: &mut BuffReaderWithPos<File>,
+    start: u64,
+    loader: &Loader,
+    active: bool,
+    last_seq: &mut u64,
+) -> Result<(u64, u64)> {
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
+    let mut uncompacted = 0u64;
+    loop {
+        // a batch is applied whole, or not at all if torn
+        let (cmds, len) = match codec.read_record(reader, gen, pos)? {
+            Frame::Record(cmd, stamp, len) => (vec![(cmd, stamp, 0..len)], len),
+            Frame::Batch(cmds, len) => (cmds, len),
+            Frame::End => break,
+            Frame::Torn if active => break,
+            Frame::Torn => return Err(KvsError::Corruption { gen, offset: pos }),
+        };
+        for (cmd, Stamp { seq, time }, range) in cmds {
+            let seq = loaded_seq(seq, last_seq);
+            let cmd_pos = CommandPos {
+                time: time.unwrap_or(loader.now),
+                ..(gen, pos + range.start..pos + range.end, seq).into()
+            };
+            let (key, version) = match cmd {
+                Command::Set { key, expires, .. } => {
+                    let cmd_pos = CommandPos { expires, ..cmd_pos };
+                    let removed = false;
+                    (key, Version { cmd_pos, removed })
                 }
+                Command::Remove { key } => {
+                    let removed = true;
+                    (key, Version { cmd_pos, removed })
+                }
+            };
+            uncompacted += loader.load(key, version);
+        }
+        pos += len;
+    }
+
+    Ok((uncompacted, pos))
+}
+
+/// Applies loaded commands to an index, and to the history of its keys if the store keeps
+/// old versions.
+struct Loader<'a> {
+    index: &'a Index,
+    history: Option<&'a History>,
+    // when loading started, which expiries are checked against
+    now: u64,
+}
+
+impl<'a> Loader<'a> {
+    fn new(index: &'a Index, history: Option<&'a History>) -> Self {
+        Loader {
+            index,
+            history,
+            now: now_millis(),
+        }
+    }
+
+    /// Point `key` at its loaded `version`, or drop the key if the version removed it or
+    /// expired already, and return the bytes this made garbage.
+    fn load(&self, key: Vec<u8>, version: Version) -> u64 {
+        let cmd_pos = version.cmd_pos;
+        let expired = !version.removed && cmd_pos.is_expired(self.now);
+        let mut history = self.history.map(|history| history.lock().unwrap());
+        let old = if version.removed || expired {
+            self.index.remove(&key).map(|entry| entry.value().load())
+        } else {
+            update_index(self.index, &key, cmd_pos)
+        };
+        if let Some(history) = &mut history {
+            keep_history(history, &key, old, version.removed.then_some(cmd_pos));
+            // as compaction left it, an expired value is an old version if its key has others
+            if let Some(versions) = history.get_mut(&key).filter(|_| expired) {
+                versions.push(version);
             }
         }
-        pos = new_pos;
+        old.map_or(0, |old| old.len) + if expired { cmd_pos.len } else { 0 }
     }
+}
 
-    Ok(uncompacted)
+/// Returns the sequence number of a loaded command, `recorded` if the codec keeps them and
+/// else the one after `last_seq`, which moves on to the highest number seen.
+fn loaded_seq(recorded: Option<u64>, last_seq: &mut u64) -> u64 {
+    let seq = recorded.unwrap_or(*last_seq + 1);
+    *last_seq = (*last_seq).max(seq);
+    seq
 }
 
-fn log_file_path(dir: &Path, gen: u64) -> PathBuf {
+pub(crate) fn log_file_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+/// Path of the compacted log `gen` while a snapshot still reads from it.
+pub(crate) fn stale_log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.stale", gen))
+}
+
+/// Open the log `gen` for reading, wherever compaction left it for a snapshot.
+fn open_log_file(dir: &Path, gen: u64) -> io::Result<File> {
+    match File::open(log_file_path(dir, gen)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => File::open(stale_log_path(dir, gen)),
+        file => file,
+    }
+}
+
+/// Remove the logs, hint files and `format` file of the store in `dir` but those named in
+/// `kept`, leaving its lock.
+fn remove_store_files(dir: &Path, kept: &[&str]) -> Result<()> {
+    const EXTENSIONS: &[&str] = &["log", "hint", "compacting", "tmp", "stale"];
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let store_file = path.file_name() == Some(FORMAT_FILE.as_ref())
+            || path
+                .extension()
+                .is_some_and(|ext| EXTENSIONS.iter().any(|&store_ext| ext == store_ext));
+        let kept = kept.iter().any(|&name| entry.file_name() == name);
+        if path.is_file() && store_file && !kept {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite the store in `path`, which uses the legacy codec, in the binary one.
+///
+/// The live pairs are written to a single log in `RESTORE_DIR`, which is then moved in place
+/// like a restored checkpoint, so that a crash leaves either store whole.
+fn upgrade_legacy_store(path: &Path) -> Result<()> {
+    let legacy = KvStore::open_read_only(path)?;
+    let copy = path.join(RESTORE_DIR);
+    if copy.is_dir() {
+        fs::remove_dir_all(&copy)?;
+    }
+    create_dir_all(&copy)?;
+    let codec = Codec::Binary;
+    let mut writer = BufWriter::new(File::create(log_file_path(&copy, 1))?);
+    let now = now_millis();
+    for (seq, pair) in (1..).zip(legacy.iter().bytes()) {
+        let (key, value) = pair?;
+        let cmd = Command::Set {
+            key,
+            value,
+            expires: None,
+        };
+        codec.write_record(&mut writer, &cmd, seq, now)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    codec.save(&copy)?;
+    sync_dir(&copy)?;
+    write_restore_manifest(&copy)?;
+    info!(
+        "Rewrote the legacy store in {} in the binary format",
+        path.display()
+    );
+    finish_restore(path)
+}
+
+/// List the files of the complete copy in `copy` in its manifest, see `finish_restore`.
+fn write_restore_manifest(copy: &Path) -> Result<()> {
+    let mut manifest = String::new();
+    for entry in read_dir(copy)? {
+        manifest.push_str(&entry?.file_name().to_string_lossy());
+        manifest.push('\n');
+    }
+    // the manifest is renamed into place, so that it is never seen incomplete
+    let tmp_path = copy.join(format!("{}.tmp", RESTORE_MANIFEST));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(manifest.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(tmp_path, copy.join(RESTORE_MANIFEST))?;
+    sync_dir(copy)
+}
+
+/// Move the files of the copy a restore completed in `dir`, if any, in place of those of the
+/// store there.
+///
+/// Every step can be taken again, so that it also finishes a restore which stopped midway.
+fn finish_restore(dir: &Path) -> Result<()> {
+    let copy = dir.join(RESTORE_DIR);
+    let manifest_path = copy.join(RESTORE_MANIFEST);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+    let manifest = fs::read_to_string(manifest_path)?;
+    let names: Vec<_> = manifest.lines().collect();
+    for name in &names {
+        let path = copy.join(name);
+        if path.exists() {
+            fs::rename(path, dir.join(name))?;
+        }
+    }
+    // the files of the old store left are the ones the copy has no namesake of
+    remove_store_files(dir, &names)?;
+    sync_dir(dir)?;
+    fs::remove_file(copy.join(RESTORE_MANIFEST))?;
+    fs::remove_dir(&copy)?;
+    sync_dir(dir)
+}
+
+/// Link the logs of `src` older than `gen` into `dest` along with their hint files, and
+/// copy the first `len` bytes of the log `gen`.
+fn copy_logs(src: &Path, dest: &Path, gen: u64, len: u64) -> Result<()> {
+    for sealed in sorted_gen_list(src)?
+        .into_iter()
+        .filter(|&sealed| sealed < gen)
+    {
+        link_or_copy(&log_file_path(src, sealed), &log_file_path(dest, sealed))?;
+        let hint_path = hint_file_path(src, sealed);
+        if hint_path.exists() {
+            link_or_copy(&hint_path, &hint_file_path(dest, sealed))?;
+        }
+    }
+    // a store which loaded nothing has no log yet
+    if len == 0 {
+        return Ok(());
+    }
+    let mut log = File::open(log_file_path(src, gen))?.take(len);
+    let mut copy = File::create(log_file_path(dest, gen))?;
+    io::copy(&mut log, &mut copy)?;
+    copy.sync_all()?;
+    Ok(())
+}
+
+/// Hard-link `src` to `dest`, or copy it if the filesystem does not allow it.
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+        File::open(dest)?.sync_all()?;
+    }
+    Ok(())
+}
+
 /// create sorted list of generated log file number
-fn sorted_gen_list(path: &PathBuf) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = read_dir(&path)?
+pub(crate) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = read_dir(path)?
         .flat_map(|it| -> Result<_> { Ok(it?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
@@ -230,23 +1627,17 @@ fn sorted_gen_list(path: &PathBuf) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
-#[derive(Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-}
-
-struct BuffReaderWithPos<R: Read + Seek> {
+pub(crate) struct BuffReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
 }
 
 impl<R: Read + Seek> BuffReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
 
         Ok(BuffReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -267,16 +1658,16 @@ impl<R: Read + Seek> Seek for BuffReaderWithPos<R> {
     }
 }
 
-struct BuffWriterWithPos<W: Write + Seek> {
-    writer: BufWriter<W>,
-    pos: u64,
+pub(crate) struct BuffWriterWithPos<W: Write + Seek> {
+    pub(crate) writer: BufWriter<W>,
+    pub(crate) pos: u64,
 }
 
 impl<W: Write + Seek> BuffWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    pub(crate) fn with_capacity(capacity: usize, mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BuffWriterWithPos {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -301,19 +1692,33 @@ impl<W: Write + Seek> Seek for BuffWriterWithPos<W> {
     }
 }
 
-// represent position and length of json-serialized command in log file
-struct CommandPos {
-    gen: u64, // log file number
-    pos: u64, // seek position in log file
-    len: u64, // length to read after seek position
+// represent position and length of an encoded command record in log file
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CommandPos {
+    pub(crate) gen: u64,             // log file number
+    pub(crate) pos: u64,             // seek position in log file
+    pub(crate) len: u64,             // length to read after seek position
+    pub(crate) seq: u64,             // sequence number of the command, the version of its key
+    pub(crate) expires: Option<u64>, // expiry of the value, in milliseconds since the epoch
+    pub(crate) time: u64,            // when the command was written, in the same unit
+}
+
+impl CommandPos {
+    /// Whether the value has expired at `now`, in milliseconds since the Unix epoch.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
+impl From<(u64, Range<u64>, u64)> for CommandPos {
+    fn from((gen, range, seq): (u64, Range<u64>, u64)) -> Self {
         CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            seq,
+            expires: None,
+            time: 0,
         }
     }
 }
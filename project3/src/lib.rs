@@ -1,9 +1,35 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use batch::WriteBatch;
+pub use client::KvsClient;
+pub use engine::{check_engine, resolve_engine, KvsEngine};
+pub use error::{CompareAndSwapError, CompareAndSwapResult, KvsError, Result};
+pub use export::ExportFormat;
+pub use kv::{KeyVersion, KvStore};
+pub use options::{CompactionPolicy, Durability, KvStoreOptions, Retention};
+pub use scan::{FromBytes, Keys, Scan};
+pub use server::KvsServer;
+pub use sled_engine::SledKvsEngine;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
 
+mod batch;
+mod client;
+pub mod common;
+mod compaction;
+mod engine;
 mod error;
+mod export;
+mod group_commit;
+mod hint;
 
 mod kv;
+mod options;
+mod record;
+mod scan;
+mod server;
+mod sled_engine;
+mod snapshot;
+pub mod thread_pool;
+mod transaction;
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+/// Default size of the buffers of the log file readers and writers.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// When `KvStore` starts a background compaction.
+///
+/// The garbage counted by these policies is the bytes of the log records which were
+/// overwritten or removed since the last compaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once there are more than this many bytes of garbage.
+    Bytes(u64),
+    /// Compact once garbage makes up more than this fraction, between 0 and 1, of the
+    /// bytes of the records in the logs.
+    GarbageRatio(f64),
+    /// Never compact in the background, only through `KvStore::compact`.
+    Manual,
+}
+
+impl CompactionPolicy {
+    /// Returns whether `garbage` bytes next to `live` bytes of live records call for a
+    /// compaction.
+    pub(crate) fn should_compact(self, garbage: u64, live: u64) -> bool {
+        match self {
+            CompactionPolicy::Bytes(threshold) => garbage > threshold,
+            CompactionPolicy::GarbageRatio(ratio) => {
+                garbage > 0 && garbage as f64 > ratio * (garbage + live) as f64
+            }
+            CompactionPolicy::Manual => false,
+        }
+    }
+}
+
+/// When a write to `KvStore` reaches the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Every write is synced to the disk before it returns.
+    Sync,
+    /// Writes are synced before they return, but concurrent writes share one sync. The
+    /// first writer of a group waits `interval` for others to join it.
+    GroupCommit {
+        /// How long a group collects writes before syncing them.
+        interval: Duration,
+    },
+    /// Writes are handed to the OS before they return, and survive a crash of the process
+    /// but not of the machine.
+    Buffered,
+}
+
+/// Which old versions of its keys a `KvStore` keeps, see `KvStore::history`.
+///
+/// The old versions are kept in the logs through compactions, and their positions in memory.
+/// A version counts as old once a later command on its key supersedes it, and a removal of the
+/// key is a version of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep no old version.
+    Off,
+    /// Keep the given number of old versions of each key.
+    Versions(usize),
+    /// Keep the old versions superseded within the given time. The versions of a removed key
+    /// all go once its removal is that old.
+    Age(Duration),
+}
+
+/// Options to open a `KvStore` with, see `KvStore::open_with`.
+///
+/// ```
+/// use kvs::{CompactionPolicy, KvStoreOptions};
+///
+/// let options = KvStoreOptions::new()
+///     .compaction(CompactionPolicy::GarbageRatio(0.5))
+///     .max_log_size(64 * 1024 * 1024);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) compaction: CompactionPolicy,
+    pub(crate) durability: Durability,
+    pub(crate) max_log_size: u64,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) read_only: bool,
+    pub(crate) retention: Retention,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options: a writable store, compaction after 1 MiB of garbage,
+    /// buffered writes, logs that grow without limit, 8 KiB buffers, and no old version kept.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            compaction: CompactionPolicy::Bytes(1024 * 1024),
+            durability: Durability::Buffered,
+            max_log_size: u64::MAX,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            read_only: false,
+            retention: Retention::Off,
+        }
+    }
+
+    /// Sets when to compact the logs.
+    pub fn compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = policy;
+        self
+    }
+
+    /// Sets when writes reach the disk.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the size in bytes after which the active log is sealed and writes roll over
+    /// to a new generation.
+    pub fn max_log_size(mut self, size: u64) -> Self {
+        self.max_log_size = size;
+        self
+    }
+
+    /// Sets the buffer size of each log file reader.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the buffer size of the log file writer.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Sets whether to open the store read-only.
+    ///
+    /// A read-only store does not take the lock of the directory, so it can be opened while
+    /// another process writes to it. Its writes fail with `KvsError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets which old versions of the keys to keep.
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,440 @@
+//! Encoding of the commands stored in the log files.
+//!
+//! The codec of a store is recorded in the `format` file of its directory. Every record is
+//! laid out as
+//!
+//! ```text
+//! head_crc: u32 | crc: u32 | kind: u8 | seq: u64 | time: u64 | key_len: u32 | value_len: u32
+//! ```
+//!
+//! followed by its payload, with integers in little endian, `head_crc` the CRC32 of the rest
+//! of the header and `crc` the CRC32 of everything after it. `seq` is the sequence number of
+//! the command, which grows with every command written to the store, and `time` when it was
+//! written, in milliseconds since the Unix epoch. The payload holds the key, then the value.
+//! `kind` is `KIND_SET`, `KIND_SET_EXPIRING` or `KIND_REMOVE`, and a remove has no value. The
+//! payload of `KIND_SET_EXPIRING` starts with the expiry of the value, an `u64` in the same
+//! unit as `time`, in front of the key.
+//!
+//! A batch of commands is a single record of kind `KIND_BATCH`, where `seq` is the sequence
+//! number of its first command, `key_len` holds the number of commands and `value_len` the
+//! length of the payload. The payload is the records of the commands, each complete with its
+//! own header, so that the index can point to them one by one.
+//!
+//! Since `head_crc` vouches for the lengths, a record running past the end of the log was
+//! cut short by a crash, while a damaged length is reported as corruption. A log ending in
+//! zeros, which a crash can leave behind as well, reads as cut short too.
+//!
+//! Stores created before the `format` file existed have none, and their logs hold the JSON
+//! serialization of each command, one right after the other, such as
+//! `{"Set":{"key":"key1","value":"value1"}}`. They are read as they are, but never written
+//! to: opening one for writing rewrites it in the binary format first. Nothing tells where
+//! their records end, so a record cut short by a crash reads as corruption.
+
+use crate::{KvsError, Result};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the file recording the codec version of a store.
+pub(crate) const FORMAT_FILE: &str = "format";
+
+/// Length of the header in front of every payload.
+const HEADER_LEN: u64 = 33;
+
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_SET_EXPIRING: u8 = 4;
+
+pub(crate) enum Command {
+    /// Sets `key` to `value`, until `expires` if any, in milliseconds since the Unix epoch.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit of expiries.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// `Command` as serialized by the legacy codec.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// What a record tells about its command besides the command itself, as far as the codec
+/// records it.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Stamp {
+    /// The sequence number of the command.
+    pub(crate) seq: Option<u64>,
+    /// When the command was written, in milliseconds since the Unix epoch.
+    pub(crate) time: Option<u64>,
+}
+
+/// Result of reading one record from a log file.
+pub(crate) enum Frame {
+    /// A complete record, its stamp, and the length of the whole record.
+    Record(Command, Stamp, u64),
+    /// A complete batch, with the stamp of each command and the range of its record within
+    /// the batch, and the length of the whole batch.
+    Batch(Vec<(Command, Stamp, Range<u64>)>, u64),
+    /// The log ends exactly here.
+    End,
+    /// The log ends in the middle of a record, e.g. the write was cut short by a crash, or
+    /// goes on with nothing but zeros.
+    Torn,
+}
+
+/// On-disk encoding of the log records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    /// JSON records, read from stores created before the `format` file existed.
+    Legacy,
+    /// The binary records described above.
+    Binary,
+}
+
+impl Codec {
+    /// Returns the codec of the store in `dir`, with the log files of `gen_list`, recording
+    /// it in the `format` file if missing.
+    pub(crate) fn load(dir: &Path, gen_list: &[u64]) -> Result<Codec> {
+        let codec = Codec::detect(dir, gen_list)?;
+        if !dir.join(FORMAT_FILE).exists() {
+            codec.save(dir)?;
+        }
+        Ok(codec)
+    }
+
+    /// Records the codec in the `format` file of `dir`, or nothing for the legacy codec,
+    /// which is told by the lack of the file.
+    pub(crate) fn save(self, dir: &Path) -> Result<()> {
+        if let Some(version) = self.version() {
+            fs::write(dir.join(FORMAT_FILE), version)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the codec of the store in `dir` like `load`, without writing anything.
+    ///
+    /// A directory without the `format` file is new if its logs are empty. Otherwise it uses
+    /// the legacy codec if its first record starts like a JSON object.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownFormat` if the `format` file names an unknown version, or
+    /// if there is none and the logs are neither empty nor JSON.
+    pub(crate) fn detect(dir: &Path, gen_list: &[u64]) -> Result<Codec> {
+        let format_file = dir.join(FORMAT_FILE);
+        if format_file.exists() {
+            let version = fs::read_to_string(&format_file)?;
+            return match version.trim() {
+                "1" => Ok(Codec::Binary),
+                version => Err(KvsError::UnknownFormat(version.to_owned())),
+            };
+        }
+        for &gen in gen_list {
+            let mut first = [0u8; 1];
+            if read_full(
+                &mut File::open(dir.join(format!("{}.log", gen)))?,
+                &mut first,
+            )? == 0
+            {
+                continue;
+            }
+            return match first {
+                [b'{'] => Ok(Codec::Legacy),
+                _ => Err(KvsError::UnknownFormat("none".to_owned())),
+            };
+        }
+        Ok(Codec::Binary)
+    }
+
+    fn version(self) -> Option<&'static str> {
+        match self {
+            Codec::Legacy => None,
+            Codec::Binary => Some("1"),
+        }
+    }
+
+    /// Writes `cmd`, whose sequence number is `seq`, as a single record written at `time`.
+    ///
+    /// # Errors
+    ///
+    /// The legacy codec returns `KvsError::ReadOnly`, it is never written.
+    pub(crate) fn write_record<W: Write>(
+        self,
+        writer: &mut W,
+        cmd: &Command,
+        seq: u64,
+        time: u64,
+    ) -> Result<()> {
+        if self == Codec::Legacy {
+            return Err(KvsError::ReadOnly);
+        }
+        writer.write_all(&encode(cmd, seq, time))?;
+        Ok(())
+    }
+
+    /// Writes `cmds`, numbered from `first_seq` on, as a single batch record written at
+    /// `time`.
+    ///
+    /// Returns the range of the record of each command within the batch.
+    pub(crate) fn write_batch<W: Write>(
+        self,
+        writer: &mut W,
+        cmds: &[Command],
+        first_seq: u64,
+        time: u64,
+    ) -> Result<Vec<Range<u64>>> {
+        if self == Codec::Legacy {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut records = Vec::new();
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for (seq, cmd) in (first_seq..).zip(cmds) {
+            let start = HEADER_LEN + records.len() as u64;
+            records.extend_from_slice(&encode(cmd, seq, time));
+            ranges.push(start..HEADER_LEN + records.len() as u64);
+        }
+
+        let count = cmds.len() as u32;
+        let len = records.len() as u32;
+        let header = header(KIND_BATCH, first_seq, time, count, len, &records);
+        writer.write_all(&header)?;
+        writer.write_all(&records)?;
+        Ok(ranges)
+    }
+
+    /// Reads the record at `offset` of the log file `gen` from `reader`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the header of a record fails its checksum, or a
+    /// complete record fails its own or does not decode to a `Command`. A batch is only
+    /// complete with all of its records. A legacy record is corrupted unless it decodes whole.
+    pub(crate) fn read_record<R: Read>(
+        self,
+        reader: &mut R,
+        gen: u64,
+        offset: u64,
+    ) -> Result<Frame> {
+        let corruption = || KvsError::Corruption { gen, offset };
+        if self == Codec::Legacy {
+            // a JSON object ends with its closing brace, so no byte after it is read
+            let mut stream = serde_json::Deserializer::from_reader(reader).into_iter();
+            let cmd = match stream.next() {
+                Some(cmd) => cmd.map_err(|_| corruption())?,
+                None => return Ok(Frame::End),
+            };
+            let cmd = match cmd {
+                LegacyCommand::Set { key, value } => Command::Set {
+                    key: key.into_bytes(),
+                    value: value.into_bytes(),
+                    expires: None,
+                },
+                LegacyCommand::Remove { key } => Command::Remove {
+                    key: key.into_bytes(),
+                },
+            };
+            let len = stream.byte_offset() as u64;
+            return Ok(Frame::Record(cmd, Stamp::default(), len));
+        }
+        let mut header = [0u8; HEADER_LEN as usize];
+        match read_full(reader, &mut header)? {
+            0 => return Ok(Frame::End),
+            n if n < header.len() => return Ok(Frame::Torn),
+            _ if zero_tail(&header, reader)? => return Ok(Frame::Torn),
+            _ => {}
+        }
+        // past its checksum, the lengths of the header can be trusted
+        let (head_crc, header) = header.split_at(4);
+        if crc32fast::hash(header) != u32::from_le_bytes(head_crc.try_into().unwrap()) {
+            return Err(corruption());
+        }
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let kind = header[4];
+        let seq = u64::from_le_bytes(header[5..13].try_into().unwrap());
+        let time = u64::from_le_bytes(header[13..21].try_into().unwrap());
+        let key_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as u64;
+        let value_len = u32::from_le_bytes(header[25..].try_into().unwrap()) as u64;
+
+        // the payload of a batch is its records, and `key_len` their number
+        let len = match kind {
+            KIND_BATCH => value_len,
+            KIND_SET_EXPIRING => 8 + key_len + value_len,
+            _ => key_len + value_len,
+        };
+        let mut payload = match read_payload(reader, len)? {
+            Some(payload) => payload,
+            None => return Ok(Frame::Torn),
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&payload);
+        if hasher.finalize() != crc {
+            return Err(corruption());
+        }
+        if kind == KIND_BATCH {
+            return self.read_batch(&payload, key_len as u32, gen, offset);
+        }
+
+        let expires = (kind == KIND_SET_EXPIRING).then(|| split_u64(&mut payload));
+        let value = payload.split_off(key_len as usize);
+        let key = payload;
+        let cmd = match kind {
+            KIND_SET | KIND_SET_EXPIRING => Command::Set {
+                key,
+                value,
+                expires,
+            },
+            KIND_REMOVE if value.is_empty() => Command::Remove { key },
+            _ => return Err(corruption()),
+        };
+        let stamp = Stamp {
+            seq: Some(seq),
+            time: Some(time),
+        };
+        Ok(Frame::Record(cmd, stamp, HEADER_LEN + len))
+    }
+
+    /// Reads the `count` records of a batch at `offset` of the log file `gen`, which follow
+    /// its header.
+    fn read_batch(self, records: &[u8], count: u32, gen: u64, offset: u64) -> Result<Frame> {
+        let mut reader = records;
+        let mut cmds = Vec::with_capacity(count as usize);
+        let mut pos = HEADER_LEN;
+        for _ in 0..count {
+            match self.read_record(&mut reader, gen, offset + pos)? {
+                Frame::Record(cmd, stamp, len) => {
+                    cmds.push((cmd, stamp, pos..pos + len));
+                    pos += len;
+                }
+                _ => return Err(KvsError::Corruption { gen, offset }),
+            }
+        }
+        if !reader.is_empty() {
+            return Err(KvsError::Corruption { gen, offset });
+        }
+        Ok(Frame::Batch(cmds, pos))
+    }
+}
+
+/// Encodes `cmd`, whose sequence number is `seq`, as a single record written at `time`.
+fn encode(cmd: &Command, seq: u64, time: u64) -> Vec<u8> {
+    let (kind, key, value, expires) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires: None,
+        } => (KIND_SET, key, value.as_slice(), None),
+        Command::Set {
+            key,
+            value,
+            expires: Some(expires),
+        } => (KIND_SET_EXPIRING, key, value.as_slice(), Some(expires)),
+        Command::Remove { key } => (KIND_REMOVE, key, &[][..], None),
+    };
+    let mut payload = Vec::with_capacity(8 + key.len() + value.len());
+    if let Some(expires) = expires {
+        payload.extend_from_slice(&expires.to_le_bytes());
+    }
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(value);
+
+    let (key_len, value_len) = (key.len() as u32, value.len() as u32);
+    let mut record = header(kind, seq, time, key_len, value_len, &payload).to_vec();
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// Returns the header of a record of `kind` with `payload`, checksums included.
+fn header(
+    kind: u8,
+    seq: u64,
+    time: u64,
+    key_len: u32,
+    value_len: u32,
+    payload: &[u8],
+) -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[8] = kind;
+    header[9..17].copy_from_slice(&seq.to_le_bytes());
+    header[17..25].copy_from_slice(&time.to_le_bytes());
+    header[25..29].copy_from_slice(&key_len.to_le_bytes());
+    header[29..].copy_from_slice(&value_len.to_le_bytes());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[8..]);
+    hasher.update(payload);
+    header[4..8].copy_from_slice(&hasher.finalize().to_le_bytes());
+    let head_crc = crc32fast::hash(&header[4..]);
+    header[..4].copy_from_slice(&head_crc.to_le_bytes());
+    header
+}
+
+/// Removes the `u64` at the start of `payload`, which holds at least 8 bytes, and returns it.
+fn split_u64(payload: &mut Vec<u8>) -> u64 {
+    let rest = payload.split_off(8);
+    let value = u64::from_le_bytes(payload[..].try_into().unwrap());
+    *payload = rest;
+    value
+}
+
+/// Reads a payload of `len` bytes, or returns `None` if `reader` ends before that.
+fn read_payload<R: Read>(reader: &mut R, len: u64) -> io::Result<Option<Vec<u8>>> {
+    // `take` instead of a preallocated buffer, so a corrupted length cannot
+    // make us allocate gigabytes
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+/// Returns whether `header` and the rest of `reader` are all zeros, as a crash can leave at
+/// the end of a log whose length reached the disk before its data.
+///
+/// The rest of `reader` is only read if `header` is all zeros.
+fn zero_tail<R: Read>(header: &[u8], reader: &mut R) -> io::Result<bool> {
+    if header.iter().any(|&byte| byte != 0) {
+        return Ok(false);
+    }
+    let mut buf = [0u8; 4096];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) if buf[..n].iter().any(|&byte| byte != 0) => return Ok(false),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads until `buf` is full or the end of `reader`, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
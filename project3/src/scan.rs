@@ -0,0 +1,132 @@
+//! Iteration over the keys of a `KvStore` or `Snapshot` in order, and over their values.
+
+use crate::kv::{CommandPos, KvStoreReader};
+use crate::Result;
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+/// Returns the bound right after the keys starting with `prefix`.
+///
+/// That is `prefix` with its last byte incremented, after dropping the bytes which cannot be.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Keys in a range and the positions of their values, as walked by a `Scan`.
+pub(crate) type Entries<'a> =
+    Box<dyn DoubleEndedIterator<Item = (Vec<u8>, CommandPos)> + Send + 'a>;
+
+/// Types the keys and values of a `Scan` can be read as: `String` and `Vec<u8>`.
+pub trait FromBytes: Sized {
+    /// Converts the bytes of a key or value.
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self>;
+}
+
+impl FromBytes for Vec<u8> {
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Ok(bytes)
+    }
+}
+
+impl FromBytes for String {
+    /// It returns `KvsError::Utf8` if the bytes are not UTF-8.
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+/// Iterator over the key/value pairs in a range of keys, see `KvStore::scan`.
+///
+/// The pairs are read as `T`.
+pub struct Scan<'a, T = String> {
+    entries: Entries<'a>,
+    reader: &'a KvStoreReader,
+    decode: PhantomData<T>,
+}
+
+impl<'a> Scan<'a> {
+    /// Returns a scan of `entries`, reading their values with `reader`.
+    pub(crate) fn new(entries: Entries<'a>, reader: &'a KvStoreReader) -> Self {
+        Scan {
+            entries,
+            reader,
+            decode: PhantomData,
+        }
+    }
+
+    /// Reads the pairs as bytes, so that keys and values need not be UTF-8.
+    pub fn bytes(self) -> Scan<'a, Vec<u8>> {
+        Scan {
+            entries: self.entries,
+            reader: self.reader,
+            decode: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: FromBytes> Scan<'a, T> {
+    /// Iterates over the keys alone, without reading any value.
+    pub fn keys(self) -> Keys<'a, T> {
+        Keys {
+            entries: self.entries,
+            decode: PhantomData,
+        }
+    }
+
+    fn read_next(
+        &mut self,
+        next: impl FnOnce(&mut Entries<'a>) -> Option<(Vec<u8>, CommandPos)>,
+    ) -> Option<Result<(T, T)>> {
+        let reader = self.reader;
+        // taken before the entry, see `KvStore::get_bytes`
+        let _read = reader.reads.enter();
+        let (key, cmd_pos) = next(&mut self.entries)?;
+        let pair = reader
+            .read_value(cmd_pos)
+            .and_then(|value| Ok((T::from_bytes(key)?, T::from_bytes(value)?)));
+        Some(pair)
+    }
+}
+
+impl<T: FromBytes> Iterator for Scan<'_, T> {
+    type Item = Result<(T, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next(Iterator::next)
+    }
+}
+
+impl<T: FromBytes> DoubleEndedIterator for Scan<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.read_next(DoubleEndedIterator::next_back)
+    }
+}
+
+/// Iterator over the keys in a range, see `Scan::keys`.
+pub struct Keys<'a, T = String> {
+    entries: Entries<'a>,
+    decode: PhantomData<T>,
+}
+
+impl<T: FromBytes> Iterator for Keys<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        let (key, _) = self.entries.next()?;
+        Some(T::from_bytes(key))
+    }
+}
+
+impl<T: FromBytes> DoubleEndedIterator for Keys<'_, T> {
+    fn next_back(&mut self) -> Option<Result<T>> {
+        let (key, _) = self.entries.next_back()?;
+        Some(T::from_bytes(key))
+    }
+}
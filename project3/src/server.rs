@@ -0,0 +1,66 @@
+use crate::common::{Request, Response};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsEngine, Result};
+use log::{debug, error};
+use serde_json::Deserializer;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// The server of a key value store.
+///
+/// Each connection is served by a job in the thread pool `P`.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a `KvsServer` with a given storage engine and thread pool.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer { engine, pool }
+    }
+
+    /// Runs the server listening on the given address.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    self.pool.spawn(move || {
+                        if let Err(e) = serve(engine, stream) {
+                            error!("Error on serving client: {}", e);
+                        }
+                    })
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+
+    for req in req_reader {
+        let req = req?;
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        let resp = match req {
+            Request::Get { key } => engine.get(key),
+            Request::Set { key, value } => engine.set(key, value).map(|_| None),
+            Request::Remove { key } => engine.remove(key).map(|_| None),
+        };
+        let resp = match resp {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err(e.into()),
+        };
+        serde_json::to_writer(&mut writer, &resp)?;
+        writer.flush()?;
+        debug!("Response sent to {}: {:?}", peer_addr, resp);
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Snapshots of a `KvStore`, and the logs kept on disk for them.
+
+use crate::kv::{log_file_path, stale_log_path, CommandPos, KvStoreReader};
+use crate::scan::{prefix_end, Scan};
+use crate::Result;
+use log::error;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A read-only view of a `KvStore` at one point in time, see `KvStore::snapshot`.
+pub struct Snapshot {
+    // the keys in order, with the positions of their values
+    entries: Vec<(Vec<u8>, CommandPos)>,
+    // the generations of the logs the positions point to
+    gens: BTreeSet<u64>,
+    // a reader of its own, whose log handles the store never closes
+    reader: KvStoreReader,
+    snapshots: Arc<SnapshotRegistry>,
+    // keeps the directory locked, so that no other store deletes the logs kept for it
+    _lock: Option<Arc<File>>,
+}
+
+impl Snapshot {
+    /// Returns a snapshot of `entries`, the keys in order with the positions of their values,
+    /// read with `reader`, which keeps the logs they point to in `snapshots`.
+    pub(crate) fn new(
+        entries: Vec<(Vec<u8>, CommandPos)>,
+        reader: KvStoreReader,
+        snapshots: Arc<SnapshotRegistry>,
+        lock: Option<Arc<File>>,
+    ) -> Snapshot {
+        let gens: BTreeSet<_> = entries.iter().map(|(_, cmd_pos)| cmd_pos.gen).collect();
+        snapshots.acquire(&gens);
+        Snapshot {
+            entries,
+            gens,
+            reader,
+            snapshots,
+            _lock: lock,
+        }
+    }
+
+    /// Returns the value of `key` as a string.
+    ///
+    /// It returns `KvsError::Utf8` if the value is not UTF-8.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of `key` when the snapshot was taken.
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        match self
+            .entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+        {
+            Ok(i) => Ok(Some(self.reader.read_value(self.entries[i].1)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Returns the key/value pairs with keys in `range`, in key order, see `KvStore::scan`.
+    pub fn scan<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Scan<'_> {
+        // the index of the first key after `key`, or at `key` too if `at`
+        let after = |key: &K, at: bool| {
+            let key = key.as_ref();
+            self.entries
+                .partition_point(|(k, _)| k.as_slice() < key || (!at && k.as_slice() == key))
+        };
+        let start = match range.start_bound() {
+            Bound::Included(key) => after(key, true),
+            Bound::Excluded(key) => after(key, false),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => after(key, false),
+            Bound::Excluded(key) => after(key, true),
+            Bound::Unbounded => self.entries.len(),
+        };
+        let entries = self.entries[start..end.max(start)]
+            .iter()
+            .map(|(key, cmd_pos)| (key.clone(), *cmd_pos));
+        Scan::new(Box::new(entries), &self.reader)
+    }
+
+    /// Returns every key/value pair in key order, see `scan`.
+    pub fn iter(&self) -> Scan<'_> {
+        self.scan::<&[u8]>(..)
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order, see `scan`.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        let prefix = prefix.as_ref();
+        self.scan((Bound::Included(prefix.to_vec()), prefix_end(prefix)))
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the snapshot has no key.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // close the logs before they may be deleted
+        if let Some(readers) = Arc::get_mut(&mut self.reader.readers) {
+            readers.clear();
+        }
+        if let Err(e) = self.snapshots.release(&self.reader.path, &self.gens) {
+            error!("Failed to delete the logs of a snapshot: {}", e);
+        }
+    }
+}
+
+/// The logs still read by live snapshots.
+///
+/// Compaction moves such a log aside as `<gen>.log.stale` instead of deleting it, and the
+/// last snapshot reading from it deletes it once dropped.
+#[derive(Default)]
+pub(crate) struct SnapshotRegistry {
+    state: Mutex<SnapshotState>,
+}
+
+#[derive(Default)]
+struct SnapshotState {
+    // number of live snapshots reading from each generation
+    refs: HashMap<u64, usize>,
+    // the compacted generations kept for them
+    stale: HashSet<u64>,
+}
+
+impl SnapshotRegistry {
+    /// Registers a snapshot reading from the logs of `gens`.
+    fn acquire(&self, gens: &BTreeSet<u64>) {
+        let mut state = self.state.lock().unwrap();
+        for &gen in gens {
+            *state.refs.entry(gen).or_default() += 1;
+        }
+    }
+
+    /// Unregisters a snapshot reading from the logs of `gens`, deleting the compacted ones
+    /// no other snapshot reads from.
+    fn release(&self, dir: &Path, gens: &BTreeSet<u64>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for &gen in gens {
+            let refs = state.refs.get_mut(&gen).unwrap();
+            *refs -= 1;
+            if *refs == 0 {
+                state.refs.remove(&gen);
+                if state.stale.remove(&gen) {
+                    fs::remove_file(stale_log_path(dir, gen))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the compacted log `gen`, or moves it aside if a snapshot still reads from it.
+    pub(crate) fn remove_log(&self, dir: &Path, gen: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.refs.contains_key(&gen) {
+            fs::rename(log_file_path(dir, gen), stale_log_path(dir, gen))?;
+            state.stale.insert(gen);
+        } else {
+            fs::remove_file(log_file_path(dir, gen))?;
+        }
+        Ok(())
+    }
+}
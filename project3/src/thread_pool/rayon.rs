@@ -0,0 +1,24 @@
+use super::ThreadPool;
+use crate::Result;
+use log::error;
+
+/// Wrapper of `rayon::ThreadPool`
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // rayon aborts the process on a panicking job unless a handler is set
+            .panic_handler(|_| error!("A job in the thread pool panicked."))
+            .build()?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
@@ -0,0 +1,70 @@
+use super::ThreadPool;
+use crate::Result;
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, error};
+use std::thread;
+
+/// A thread pool using a shared queue inside.
+///
+/// If a spawned task panics, the old thread will be destroyed and a new one will be
+/// created. A failure to create the replacement thread is only logged, so the thread
+/// number in the pool can decrease to zero, then spawning a task to the thread pool
+/// will panic.
+pub struct SharedQueueThreadPool {
+    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (tx, rx) = crossbeam_channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+        for _ in 0..threads {
+            let rx = TaskReceiver(rx.clone());
+            thread::Builder::new().spawn(move || run_tasks(rx))?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+
+    /// Spawns a function into the thread pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool has no thread.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tx
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+#[derive(Clone)]
+struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+
+impl Drop for TaskReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let rx = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+// Replaces the worker through `TaskReceiver::drop` when a task panics, instead of
+// `catch_unwind`, which would require every task to be `UnwindSafe`.
+fn run_tasks(rx: TaskReceiver) {
+    loop {
+        match rx.0.recv() {
+            Ok(task) => {
+                task();
+            }
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
+        }
+    }
+}
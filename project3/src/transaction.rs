@@ -0,0 +1,87 @@
+use crate::kv::KvStore;
+use crate::{KvsError, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// Reads and writes which `KvStore::transaction` commits all together, unless a key the
+/// transaction read was written by someone else meanwhile.
+///
+/// Reads see the writes made earlier in the transaction, which stay pending until the
+/// commit.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("balance".to_owned(), "10".to_owned())?;
+///
+/// store.transaction(|txn| {
+///     let balance: u32 = txn.get("balance")?.unwrap().parse().unwrap();
+///     txn.set("balance", (balance - 3).to_string());
+///     assert_eq!(txn.get("balance")?, Some("7".to_owned()));
+///     Ok(())
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // version of each key read from the store, `None` if it was missing
+    pub(crate) reads: HashMap<Vec<u8>, Option<u64>>,
+    // pending value of each key written, `None` if removed
+    pub(crate) writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Self {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value of `key` as a string.
+    ///
+    /// It returns `KvsError::Utf8` if the value is not UTF-8.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of `key`, as written by the transaction or else as in the store.
+    pub fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, seq) = match self.store.get_with_seq(key)? {
+            Some((value, seq)) => (Some(value), Some(seq)),
+            None => (None, None),
+        };
+        // the commit checks the key against the version first read
+        self.reads.entry(key.to_vec()).or_insert(seq);
+        Ok(value)
+    }
+
+    /// Sets `key` to `value` when the transaction commits.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.writes
+            .insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+    }
+
+    /// Removes `key` when the transaction commits.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist at this point of the
+    /// transaction.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        if self.get_bytes(key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
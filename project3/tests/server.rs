@@ -0,0 +1,186 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsError, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Kills the server process when dropped, even if the test fails halfway.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Returns a local address nothing listens on, so that each test runs a server of its own.
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// Starts `kvs-server` in `dir`, listening on `addr`, and waits until it accepts connections.
+fn start_server(dir: &TempDir, addr: &str, engine: &str) -> ServerGuard {
+    start_server_with_args(dir, addr, &["--engine", engine])
+}
+
+fn start_server_with_args(dir: &TempDir, addr: &str, args: &[&str]) -> ServerGuard {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let mut server = ServerGuard(child);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(server.0.try_wait().unwrap().is_none(), "kvs-server exited");
+        assert!(Instant::now() < deadline, "kvs-server is not listening");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server
+}
+
+fn client_round_trip(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, addr, engine);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    // Removing a missing key is reported by the server and fails the client.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    drop(server);
+}
+
+#[test]
+fn client_cli_kvs_engine() {
+    client_round_trip("kvs", &free_addr());
+}
+
+#[test]
+fn client_cli_sled_engine() {
+    client_round_trip("sled", &free_addr());
+}
+
+#[test]
+fn client_cli_invalid_subcommand() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["unknown", "key1"])
+        .assert()
+        .failure();
+}
+
+// `kvs-server` refuses to open a directory created by the other engine.
+#[test]
+fn server_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &free_addr(), "kvs");
+    drop(server);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", &free_addr()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Errors from the engine reach `KvsClient` as structured `KvsError`s.
+#[test]
+fn client_structured_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let _server = start_server(&temp_dir, &addr, "kvs");
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        client.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    // The connection stays usable after an error.
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Many clients talking to the server at once, with each kind of thread pool.
+fn concurrent_clients(pool: &str, addr: &str) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _server = start_server_with_args(&temp_dir, addr, &["--pool", pool]);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let addr = addr.to_owned();
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(&addr)?;
+                for i in 0..50 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    client.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_clients_naive_pool() -> Result<()> {
+    concurrent_clients("naive", &free_addr())
+}
+
+#[test]
+fn concurrent_clients_shared_queue_pool() -> Result<()> {
+    concurrent_clients("shared-queue", &free_addr())
+}
+
+#[test]
+fn concurrent_clients_rayon_pool() -> Result<()> {
+    concurrent_clients("rayon", &free_addr())
+}
@@ -1,8 +1,18 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CompactionPolicy, Durability, ExportFormat, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    Result, Retention, SledKvsEngine, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +27,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +38,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +51,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +64,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +75,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +90,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +104,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +118,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +131,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +146,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +167,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,16 +182,65 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
+// `kvs --engine sled` should work the same way as the default engine.
+#[test]
+fn cli_sled_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key2", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+// A data directory created by one engine should not be opened by the other.
+#[test]
+fn cli_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs"));
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +250,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +261,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +270,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +282,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +298,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +306,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +318,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -289,7 +348,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -304,5 +363,2339 @@ fn compaction() -> Result<()> {
 fn open_file() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    KvStore::open(temp_dir.path());
+    KvStore::open(temp_dir.path()).expect("unable to open store");
+}
+
+// Flips every bit of the byte at `offset` of `file`.
+fn flip_byte(file: &Path, offset: u64) {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file)
+        .unwrap();
+    let mut byte = [0u8];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[!byte[0]]).unwrap();
+}
+
+// A damaged record is reported with its log file and offset when the store is opened.
+#[test]
+fn corruption_detected_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let second_record = fs::metadata(&log)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    flip_byte(&log, second_record + 22);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, second_record);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A record damaged after the store was opened is caught by `get`.
+#[test]
+fn corruption_detected_on_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    flip_byte(&temp_dir.path().join("1.log"), 20);
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Corruption { gen: 1, offset: 0 })
+    ));
+    Ok(())
+}
+
+// Cuts `len` bytes off the end of `file`, as a crash in the middle of a write would.
+fn chop(file: &Path, len: u64) {
+    let file = OpenOptions::new().write(true).open(file).unwrap();
+    let file_len = file.metadata().unwrap().len();
+    file.set_len(file_len - len).unwrap();
+}
+
+// A record torn by a crash at the end of the newest log is dropped on open.
+#[test]
+fn torn_write_in_active_log() -> Result<()> {
+    // how much of the second record survives the crash: part of the payload,
+    // part of the header, or a single byte
+    for kept in [40, 20, 1] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("1.log");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let complete_len = fs::metadata(&log)?.len();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let torn_len = fs::metadata(&log)?.len() - complete_len;
+        chop(&log, torn_len - kept);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(fs::metadata(&log)?.len(), complete_len);
+
+        // the store keeps working after the recovery
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Only a record following a complete one is taken for one torn by a crash: a log failing from
+// its first record is reported as corruption and left as is, and so is a legacy log, which
+// tells nothing of where its records end.
+#[test]
+fn torn_log_not_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    chop(&log, 10);
+    let torn = fs::read(&log)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, offset: 0 })
+    ));
+    assert_eq!(fs::read(&log)?, torn);
+
+    // the records as `serde_json::to_writer` wrote them, the second cut short
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let first = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let legacy = format!(r#"{}{{"Set":{{"key":"ke"#, first);
+    fs::write(&log, &legacy)?;
+    for read_only in [false, true] {
+        match KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(read_only)) {
+            Err(KvsError::Corruption { gen, offset }) => {
+                assert_eq!(gen, 1);
+                assert_eq!(offset, first.len() as u64);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("corruption not detected"),
+        }
+    }
+    assert_eq!(fs::read_to_string(&log)?, legacy);
+    assert!(!temp_dir.path().join("format").exists());
+    Ok(())
+}
+
+// A torn record in a sealed log is still reported as corruption.
+#[test]
+fn torn_write_in_sealed_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // opening again seals 1.log and starts writing to 2.log
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let log_len = fs::metadata(&log)?.len();
+    chop(&log, 5);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, log_len / 2);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A damaged length is reported as corruption, rather than read as a record cut short by a
+// crash, even in the last record of the newest log.
+#[test]
+fn corrupted_length_in_active_log() -> Result<()> {
+    for last in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("1.log");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let second_record = fs::metadata(&log)?.len();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        if !last {
+            store.set("key3".to_owned(), "value3".to_owned())?;
+        }
+        drop(store);
+        let log_len = fs::metadata(&log)?.len();
+
+        // the high byte of the value length, which now runs past the end of the log
+        flip_byte(&log, second_record + 32);
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::Corruption { gen, offset }) => {
+                assert_eq!(gen, 1);
+                assert_eq!(offset, second_record);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("corruption not detected"),
+        }
+        assert_eq!(fs::metadata(&log)?.len(), log_len);
+    }
+    Ok(())
+}
+
+// Zeros at the end of the newest log, left by a crash before the data reached the disk,
+// are dropped on open like a torn record.
+#[test]
+fn zero_filled_tail_in_active_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let complete_len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[0; 4096])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), complete_len);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// New stores record the binary format and write compact records.
+#[test]
+fn binary_format_for_new_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "1");
+    // 33 bytes of header, checksummed and with the set kind, the sequence number 1 and the
+    // time of the write, then the key and the value
+    let log = fs::read(temp_dir.path().join("1.log"))?;
+    assert_eq!(log.len(), 33 + 4 + 6);
+    assert_eq!(&log[..4], &crc32fast::hash(&log[4..33]).to_le_bytes());
+    assert_eq!(log[8], 1);
+    assert_eq!(&log[9..17], &1u64.to_le_bytes());
+    let time = u64::from_le_bytes(log[17..25].try_into().unwrap());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    assert!(now.as_millis() as u64 - time < 60_000);
+    assert_eq!(&log[33..], b"key1value1");
+    Ok(())
+}
+
+// `Command` as the store serialized it before the format file existed.
+#[derive(serde::Serialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+// Writes the log `gen` of a store from before the format file, with the records of `cmds`
+// serialized one right after the other, as `KvStore` did then.
+fn write_legacy_log(dir: &Path, gen: u64, cmds: &[LegacyCommand]) {
+    let mut log =
+        std::io::BufWriter::new(fs::File::create(dir.join(format!("{}.log", gen))).unwrap());
+    for cmd in cmds {
+        serde_json::to_writer(&mut log, cmd).unwrap();
+    }
+    log.flush().unwrap();
+}
+
+// Directories written with the JSON records, before the format file existed, can be read
+// without a byte of them being changed.
+#[test]
+fn open_json_format_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every open created a new log then, even one only reading
+    let set = |key: &str, value: &str| LegacyCommand::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    write_legacy_log(temp_dir.path(), 1, &[set("key1", "value1")]);
+    write_legacy_log(temp_dir.path(), 2, &[set("key2", "value2")]);
+    let remove = LegacyCommand::Remove {
+        key: "key2".to_owned(),
+    };
+    write_legacy_log(temp_dir.path(), 3, &[remove, set("key3", "value3")]);
+    write_legacy_log(temp_dir.path(), 4, &[]);
+    let logs = |dir: &Path| -> Vec<_> {
+        (1..=4)
+            .map(|gen| fs::read(dir.join(format!("{}.log", gen))).unwrap())
+            .collect()
+    };
+    let written = logs(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["get", "key1"]).success().stdout(eq("value1").trim());
+    kvs(&["get", "key2"])
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    assert_eq!(logs(temp_dir.path()), written);
+    assert!(!temp_dir.path().join("5.log").exists());
+    assert!(!temp_dir.path().join("format").exists());
+    assert!(!temp_dir.path().join("engine").exists());
+    Ok(())
+}
+
+// Directories written with the JSON records, before the format file existed, are rewritten
+// in the binary format when opened for writing.
+#[test]
+fn upgrade_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set = |key: &str, value: &str| LegacyCommand::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    write_legacy_log(
+        temp_dir.path(),
+        1,
+        &[set("key1", "value1"), set("key2", "value2")],
+    );
+    let remove = LegacyCommand::Remove {
+        key: "key2".to_owned(),
+    };
+    write_legacy_log(temp_dir.path(), 2, &[remove, set("key1", "value3")]);
+    write_legacy_log(temp_dir.path(), 3, &[]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "1");
+    assert!(!temp_dir.path().join("restoring").exists());
+    assert!(!temp_dir.path().join("3.log").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_unknown_format() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("format"), "42").unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnknownFormat(version)) if version == "42"
+    ));
+
+    // logs in no known format, without a format file, are left alone
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), [0xff; 16]).unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnknownFormat(version)) if version == "none"
+    ));
+    assert_eq!(fs::read(temp_dir.path().join("1.log")).unwrap(), [0xff; 16]);
+    assert!(!temp_dir.path().join("format").exists());
+}
+
+// Compaction writes a hint file, and opening the store uses it instead of replaying the log.
+#[test]
+fn hint_file_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    // the compacted generation is 2, and 3 holds the writes after the compaction
+    let hint = temp_dir.path().join("2.hint");
+    assert!(hint.exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..101 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    // a damaged log record goes unnoticed on open, proving the log was not replayed
+    flip_byte(&temp_dir.path().join("2.log"), 20);
+    KvStore::open(temp_dir.path())?;
+    flip_byte(&temp_dir.path().join("2.log"), 20);
+
+    // an invalid hint file is ignored and the log is replayed instead
+    flip_byte(&hint, 30);
+    check()?;
+    fs::remove_file(&hint)?;
+    check()?;
+    Ok(())
+}
+
+// Writes and reads keep going while compactions run, and no update is lost.
+#[test]
+fn compaction_concurrent_with_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let compactor = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for _ in 0..20 {
+                store.compact().unwrap();
+            }
+        })
+    };
+    for iter in 1..20 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+        assert_eq!(store.get("key999".to_owned())?, Some(format!("{}", iter)));
+    }
+    compactor.join().unwrap();
+    store.compact()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            // every other removed key was set again by the next iteration
+            let expected = if key_id == 19 {
+                None
+            } else {
+                Some("19".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // only the last compacted log and the log written after it are left
+    assert_eq!(log_count(temp_dir.path()), 2);
+
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// The files of a compaction interrupted by a crash are cleaned up on open.
+#[test]
+fn unfinished_compaction_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let unfinished = temp_dir.path().join("2.log.compacting");
+    fs::write(&unfinished, b"partial")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!unfinished.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Number of log files in `dir`.
+fn log_count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "log")
+        })
+        .count()
+}
+
+// With the manual policy, garbage piles up until `compact` is called.
+#[test]
+fn manual_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{:0100}", iter))?;
+        }
+    }
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key7".to_owned())?, Some(format!("{:0100}", 99)));
+    Ok(())
+}
+
+// The garbage ratio policy compacts once most of the log is garbage, however small it is.
+#[test]
+fn garbage_ratio_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::GarbageRatio(0.5));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..40 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // dropping the store waits for the background compaction
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// Writes roll over to a new log once the active one reaches the maximum size.
+#[test]
+fn log_rolls_at_max_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .max_log_size(1024)
+        .read_buffer_size(16)
+        .write_buffer_size(16);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{:0100}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(log_count(temp_dir.path()) >= 10);
+    for gen in 1..10 {
+        let len = fs::metadata(temp_dir.path().join(format!("{}.log", gen)))?.len();
+        assert!((1024..1024 + 200).contains(&len));
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{:0100}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    Ok(())
+}
+
+// A remove reaches the log file before it returns.
+#[test]
+fn remove_is_written_through() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    store.remove("key1".to_owned())?;
+    assert!(fs::metadata(&log)?.len() > len);
+    Ok(())
+}
+
+// Concurrent writers with group commit all get their writes through.
+#[test]
+fn group_commit_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::GroupCommit {
+        interval: Duration::from_millis(2),
+    });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for key_id in 0..50 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id)).unwrap();
+                    if key_id % 5 == 0 {
+                        store.remove(key).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            let expected = if key_id % 5 == 0 {
+                None
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// Every write acknowledged with a synced durability survives the process being killed. This
+// only kills the process: the page cache survives, so it says nothing of a power loss.
+#[test]
+fn acknowledged_writes_survive_process_kill() -> Result<()> {
+    for durability in ["sync", "group-commit"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut child = Command::new(std::env::current_exe()?)
+            .args(["crash_child", "--exact", "--ignored", "--nocapture"])
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .env("KVS_CRASH_DURABILITY", durability)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let mut acked = None;
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            if let Some(key_id) = line?.strip_prefix("acked ") {
+                let key_id: u32 = key_id.parse().unwrap();
+                acked = Some(key_id);
+                if key_id >= 300 {
+                    break;
+                }
+            }
+        }
+        child.kill()?;
+        child.wait()?;
+
+        let acked = acked.expect("the child acknowledged no write");
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..=acked {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Writes until killed by `acknowledged_writes_survive_process_kill`, printing every
+// acknowledged key.
+#[test]
+#[ignore]
+fn crash_child() -> Result<()> {
+    let dir = match std::env::var("KVS_CRASH_DIR") {
+        Ok(dir) => dir,
+        Err(_) => return Ok(()),
+    };
+    let durability = match std::env::var("KVS_CRASH_DURABILITY").as_deref() {
+        Ok("group-commit") => Durability::GroupCommit {
+            interval: Duration::from_millis(1),
+        },
+        _ => Durability::Sync,
+    };
+    let store = KvStore::open_with(dir, KvStoreOptions::new().durability(durability))?;
+    for key_id in 0..100_000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        println!("acked {}", key_id);
+    }
+    Ok(())
+}
+
+// Only one store at a time can open a directory for writing.
+#[test]
+fn directory_locked_while_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+
+    // the lock is held until the last clone is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A read-only store opens without the lock, and refuses writes.
+#[test]
+fn read_only_ignores_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let options = KvStoreOptions::new().read_only(true);
+    let read_only = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        read_only.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(read_only.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `kvs` refuses to write to a directory in use, but still reads it.
+#[test]
+fn cli_locked_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    kvs(&["set", "key1", "value2"])
+        .failure()
+        .stderr(contains("in use"));
+
+    // the commands which only read open the store read-only, and leave it as is
+    let listing = dir_listing(temp_dir.path());
+    kvs(&["get", "key1"]).success().stdout(eq("value1\n"));
+    kvs(&["count"]).success().stdout(eq("1\n"));
+    kvs(&["export"])
+        .success()
+        .stdout(eq("{\"key\":\"key1\",\"value\":\"value1\"}\n"));
+    assert_eq!(dir_listing(temp_dir.path()), listing);
+    Ok(())
+}
+
+// Names and lengths of the files in `dir`.
+fn dir_listing(dir: &Path) -> Vec<(String, u64)> {
+    let mut listing: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            (name, entry.metadata().unwrap().len())
+        })
+        .collect();
+    listing.sort();
+    listing
+}
+
+// Opening and reading a store read-only leaves its directory untouched.
+#[test]
+fn read_only_changes_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    // a torn record is left for the owner to truncate
+    fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("3.log"))?
+        .write_all(&[1, 2, 3])?;
+
+    let listing = dir_listing(temp_dir.path());
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.refresh()?;
+    drop(store);
+    assert_eq!(dir_listing(temp_dir.path()), listing);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// A read-only store picks up the writes of the owner when refreshed, compactions included.
+#[test]
+fn read_only_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(read_only.get("key3".to_owned())?, None);
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // the removal of key2 only survives in the index of the owner
+    store.remove("key2".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(read_only.get("key2".to_owned())?, None);
+    assert_eq!(read_only.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(read_only.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// A refresh between the seal and the end of a compaction, before the compacted log is renamed
+// into place, still finds the keys moved to it once it is there.
+#[test]
+fn read_only_refresh_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(path, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let read_only = KvStore::open_read_only(path)?;
+    let sealed = fs::read(path.join("1.log"))?;
+    // compacts 1.log into 2.log, and goes on writing to 3.log
+    store.compact()?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // back to the sealed log, with the compacted one not renamed yet
+    let compacted = fs::read(path.join("2.log"))?;
+    let hint = fs::read(path.join("2.hint"))?;
+    fs::remove_file(path.join("2.log"))?;
+    fs::remove_file(path.join("2.hint"))?;
+    fs::write(path.join("1.log"), &sealed)?;
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    // then to the end of the compaction
+    fs::remove_file(path.join("1.log"))?;
+    fs::write(path.join("2.log"), compacted)?;
+    fs::write(path.join("2.hint"), hint)?;
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(read_only.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(read_only.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(read_only.last_seq(), 4);
+    Ok(())
+}
+
+// Scans return the pairs in a range of keys in order, from either end.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.set("key3".to_owned(), "value33".to_owned())?;
+
+    let keys = |scan: kvs::Scan| scan.keys().collect::<Result<Vec<_>>>().unwrap();
+    let pairs = |scan: kvs::Scan| scan.collect::<Result<Vec<_>>>();
+    assert_eq!(
+        keys(store.iter()),
+        ["key0", "key1", "key2", "key3", "key4", "key6", "key7", "key8", "key9"]
+    );
+    assert_eq!(keys(store.scan("key2".."key5")), ["key2", "key3", "key4"]);
+    assert_eq!(
+        keys(store.scan("key2"..="key6")),
+        ["key2", "key3", "key4", "key6"]
+    );
+    assert_eq!(keys(store.scan(.."key2")), ["key0", "key1"]);
+    assert_eq!(
+        keys(store.scan::<&str>((Bound::Excluded("key7"), Bound::Unbounded))),
+        ["key8", "key9"]
+    );
+    assert_eq!(
+        pairs(store.scan("key2".."key5"))?,
+        [
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value33".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+    assert_eq!(
+        store
+            .scan("key2".."key5")
+            .rev()
+            .collect::<Result<Vec<_>>>()?,
+        [
+            ("key4".to_owned(), "value4".to_owned()),
+            ("key3".to_owned(), "value33".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(store.iter().keys().next_back().unwrap()?, "key9");
+
+    store.compact()?;
+    assert_eq!(pairs(store.scan("key3"..="key3"))?.len(), 1);
+    assert_eq!(store.iter().count(), 9);
+    Ok(())
+}
+
+// `kvs scan` lists a range of pairs.
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..5 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .unwrap();
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "key0\tvalue0\nkey1\tvalue1\nkey2\tvalue2\nkey3\tvalue3\nkey4\tvalue4\n",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--start", "key1", "--end", "key4", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1\tvalue1\nkey2\tvalue2\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--limit", "many"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Prefix queries see exactly the keys starting with the prefix.
+#[test]
+fn scan_prefix_and_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    for key in [
+        "user",
+        "user:1:name",
+        "user:1:profile",
+        "user:2:name",
+        "user;",
+        "users",
+        "group:1",
+        "\u{10ffff}",
+        "\u{10ffff}\u{10ffff}a",
+    ] {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    store.remove("user:2:name".to_owned())?;
+
+    assert_eq!(
+        store
+            .scan_prefix("user:")
+            .keys()
+            .collect::<Result<Vec<_>>>()?,
+        ["user:1:name", "user:1:profile"]
+    );
+    assert_eq!(
+        store.scan_prefix("user:1:").collect::<Result<Vec<_>>>()?,
+        [
+            ("user:1:name".to_owned(), "value of user:1:name".to_owned()),
+            (
+                "user:1:profile".to_owned(),
+                "value of user:1:profile".to_owned()
+            ),
+        ]
+    );
+    assert_eq!(store.count_prefix("user"), 5);
+    assert_eq!(store.count_prefix("user:3"), 0);
+    assert_eq!(store.count_prefix("\u{10ffff}"), 2);
+    assert_eq!(store.count_prefix(""), 8);
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
+    Ok(())
+}
+
+// `kvs keys` and `kvs count` enumerate a namespace.
+#[test]
+fn cli_keys_and_count() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key in ["a:1", "a:2", "b:1"] {
+        store.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    drop(store);
+
+    let kvs = |args: &[&str], stdout: &'static str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq(stdout));
+    };
+    kvs(&["keys", "--prefix", "a:"], "a:1\na:2\n");
+    kvs(&["keys"], "a:1\na:2\nb:1\n");
+    kvs(&["count", "--prefix", "b:"], "1\n");
+    kvs(&["count"], "3\n");
+}
+
+// Keys and values can be any bytes.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = [0u8, 0xff, 0xfe, b'k'];
+    let value: Vec<u8> = (0..=255).collect();
+    store.set_bytes(key, &value)?;
+    store.set_bytes(b"empty", b"")?;
+    store.set_bytes([0xffu8], [0xc0u8])?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    store.set_bytes(b"binary", [0xffu8])?;
+    store.remove_bytes(b"empty")?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_bytes(key)?, Some(value.clone()));
+        assert_eq!(store.get_bytes(b"empty")?, None);
+        assert_eq!(store.get_bytes(b"text")?, Some(b"value".to_vec()));
+        assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+        // the string API refuses values which are not UTF-8
+        assert!(matches!(
+            store.get("binary".to_owned()),
+            Err(KvsError::Utf8(_))
+        ));
+        assert_eq!(
+            store
+                .scan_prefix([0xffu8])
+                .bytes()
+                .collect::<Result<Vec<_>>>()?,
+            [(vec![0xff], vec![0xc0])]
+        );
+        assert_eq!(
+            store.iter().bytes().keys().collect::<Result<Vec<_>>>()?,
+            [
+                key.to_vec(),
+                b"binary".to_vec(),
+                b"text".to_vec(),
+                vec![0xff]
+            ]
+        );
+        assert!(store
+            .iter()
+            .any(|pair| matches!(pair, Err(KvsError::Utf8(_)))));
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A batch applies all of its commands, in order.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("list1:item".to_owned(), "value".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .remove("list1:item")
+        .set("list2:item", "value")
+        .set("key1", "value1")
+        .set("key1", "value2")
+        .set("key2", "value")
+        .remove("key2")
+        .remove("other");
+    assert_eq!(batch.len(), 7);
+    store.write(batch)?;
+    store.write(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(
+            store.iter().collect::<Result<Vec<_>>>()?,
+            [
+                ("key1".to_owned(), "value2".to_owned()),
+                ("list2:item".to_owned(), "value".to_owned()),
+            ]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A batch removing a missing key is refused whole.
+#[test]
+fn write_batch_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1").remove("key1");
+    assert!(matches!(store.write(batch), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), len);
+    Ok(())
+}
+
+// A batch torn by a crash anywhere in its record is dropped whole on open.
+#[test]
+fn torn_batch_dropped_whole() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let complete_len = fs::metadata(&log)?.len();
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value2")
+        .set("key2", "value2")
+        .remove("key2")
+        .set("key3", "value3");
+    store.write(batch)?;
+    drop(store);
+    let batch_len = fs::metadata(&log)?.len() - complete_len;
+    let batch_log = fs::read(&log)?;
+
+    for kept in 1..batch_len {
+        fs::write(&log, &batch_log[..(complete_len + kept) as usize])?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(fs::metadata(&log)?.len(), complete_len);
+        drop(store);
+        // drops the log the reopened store started
+        fs::remove_file(temp_dir.path().join("2.log"))?;
+    }
+
+    fs::write(&log, &batch_log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged record inside a batch fails the whole batch.
+#[test]
+fn corrupted_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1").set("key2", "value2");
+    store.write(batch)?;
+    drop(store);
+
+    // the last byte of the value of key2
+    flip_byte(&log, fs::metadata(&log)?.len() - 1);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, offset: 0 })
+    ));
+    Ok(())
+}
+
+// Reads in a transaction see its own pending writes, which apply at the commit.
+#[test]
+fn transaction_reads_own_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let read = store.transaction(|txn| {
+        let read = txn.get("key1")?;
+        txn.set("key1", "value3");
+        assert_eq!(txn.get("key1")?, Some("value3".to_owned()));
+        txn.remove("key2")?;
+        assert_eq!(txn.get("key2")?, None);
+        txn.set("key4", "value4");
+        txn.remove("key4")?;
+        assert!(matches!(txn.remove("key4"), Err(KvsError::KeyNotFound)));
+
+        // nothing is visible outside the transaction before the commit
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(read)
+    })?;
+    assert_eq!(read, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // an error returned by the closure aborts the transaction
+    let aborted = store.transaction(|txn| {
+        txn.set("key1", "value5");
+        txn.remove("key5")
+    });
+    assert!(matches!(aborted, Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction fails to commit if a key it read was written meanwhile, including a key
+// which was missing.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let result = store.transaction(|txn| {
+        txn.get("key1")?;
+        txn.set("key2", "value2");
+        store.set("key1".to_owned(), "concurrent".to_owned())?;
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::Conflict)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let result = store.transaction(|txn| {
+        assert_eq!(txn.get("key3")?, None);
+        txn.set("key2", "value2");
+        store.set("key3".to_owned(), "concurrent".to_owned())?;
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::Conflict)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // writing a key that was not read, or compacting, is no conflict
+    store.transaction(|txn| {
+        txn.get("key1")?;
+        txn.set("key2", "value2");
+        store.set("key4".to_owned(), "concurrent".to_owned())?;
+        store.compact()?;
+        Ok(())
+    })?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the versions of the keys survive reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let result = store.transaction(|txn| {
+        txn.get("key2")?;
+        store.set("key2".to_owned(), "concurrent".to_owned())?;
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::Conflict)));
+    Ok(())
+}
+
+// Threads incrementing a counter in transactions, retried on conflict, lose no increment.
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let result = store.transaction(|txn| {
+                            let counter: u32 = txn.get("counter")?.unwrap().parse().unwrap();
+                            txn.set("counter", (counter + 1).to_string());
+                            Ok(())
+                        });
+                        match result {
+                            Ok(()) => break,
+                            Err(KvsError::Conflict) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Conditional writes apply only on the expected value, and return the current one otherwise.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.set_if_absent("key1", "value1")?, Ok(()));
+    let mismatch = store.set_if_absent("key1", "value2")?.unwrap_err();
+    assert_eq!(mismatch.current, Some(b"value1".to_vec()));
+
+    let mismatch = store
+        .compare_and_swap("key1", Some(b"value2".as_ref()), Some(b"value3".as_ref()))?
+        .unwrap_err();
+    assert_eq!(mismatch.current, Some(b"value1".to_vec()));
+    assert_eq!(
+        store.compare_and_swap("key1", Some(b"value1".as_ref()), Some(b"value3".as_ref()))?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    let mismatch = store.remove_if_equals("key1", "value1")?.unwrap_err();
+    assert_eq!(mismatch.current, Some(b"value3".to_vec()));
+    assert_eq!(store.remove_if_equals("key1", "value3")?, Ok(()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let mismatch = store.remove_if_equals("key1", "value3")?.unwrap_err();
+    assert_eq!(mismatch.current, None);
+    assert_eq!(store.compare_and_swap("key1", None, None)?, Ok(()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Threads taking a lease with `set_if_absent` never hold it at the same time, and
+// increments made with `compare_and_swap` are never lost.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let barrier = Arc::new(Barrier::new(4));
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<u32> {
+                barrier.wait();
+                let owner = thread_id.to_string();
+                let mut leases = 0;
+                for _ in 0..50 {
+                    if store.set_if_absent("lease", &owner)?.is_ok() {
+                        leases += 1;
+                        assert_eq!(store.remove_if_equals("lease", &owner)?, Ok(()));
+                    }
+
+                    let mut current = store.get_bytes("counter")?;
+                    loop {
+                        let counter: u32 = String::from_utf8(current.clone().unwrap())?
+                            .parse()
+                            .unwrap();
+                        let new = (counter + 1).to_string();
+                        match store.compare_and_swap(
+                            "counter",
+                            current.as_deref(),
+                            Some(new.as_bytes()),
+                        )? {
+                            Ok(()) => break,
+                            Err(mismatch) => current = mismatch.current,
+                        }
+                    }
+                }
+                Ok(leases)
+            })
+        })
+        .collect();
+    let mut leases = 0;
+    for handle in handles {
+        leases += handle.join().unwrap()?;
+    }
+    assert!(leases > 0);
+    assert_eq!(store.get("lease".to_owned())?, None);
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_compare_and_swap() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set-if-absent", "key1", "value1"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["set-if-absent", "key1", "value2"])
+        .failure()
+        .stdout(eq("value1\n"));
+    kvs(&["cas", "key1", "--expected", "value2", "--new", "value3"])
+        .failure()
+        .stdout(eq("value1\n"));
+    kvs(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["get", "key1"]).success().stdout(eq("value3\n"));
+    kvs(&["rm-if-equals", "key1", "value1"])
+        .failure()
+        .stdout(eq("value3\n"));
+    kvs(&["cas", "key1", "--expected", "value3"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["rm-if-equals", "key1", "value3"])
+        .failure()
+        .stdout(eq("Key not found\n"));
+    kvs(&["cas", "key1", "--new", "value4"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["get", "key1"]).success().stdout(eq("value4\n"));
+}
+
+// A key set with a TTL reads as missing once it expires, also after reopening the store.
+#[test]
+fn ttl_expires_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("session", "token", Duration::from_millis(200))?;
+    store.set_with_ttl("cache", "entry", Duration::from_secs(3600))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+    assert!(store.ttl("session")?.unwrap() <= Duration::from_millis(200));
+    assert_eq!(store.ttl("key1")?, None);
+    assert!(matches!(store.ttl("key2"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.len(), 3);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert!(matches!(store.ttl("session"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        store.remove("session".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.count_prefix("session"), 0);
+    let keys = store.iter().keys().collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["cache", "key1"]);
+    assert_eq!(store.set_if_absent("session", "token2")?, Ok(()));
+    store.set_with_ttl("session2", "token", Duration::from_millis(100))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, Some("token2".to_owned()));
+    assert_eq!(store.get("session2".to_owned())?, None);
+    let ttl = store.ttl("cache")?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    Ok(())
+}
+
+// Expired values count as garbage: they trigger compactions, which drop them.
+#[test]
+fn expired_values_are_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::GarbageRatio(0.5));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            format!("{:0100}", key_id),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("key100".to_owned(), "value".to_owned())?;
+    assert!(temp_dir.path().join("1.log").exists());
+
+    // the next write finds the expired values
+    thread::sleep(Duration::from_millis(200));
+    store.set("key101".to_owned(), "value".to_owned())?;
+    // dropping the store waits for the background compaction
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // the compacted log only holds the values which did not expire
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = store.iter().keys().collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["key100", "key101"]);
+    let size: u64 = dir_listing(temp_dir.path())
+        .into_iter()
+        .filter(|(name, _)| name.ends_with(".log"))
+        .map(|(_, len)| len)
+        .sum();
+    assert!(size < 1000);
+
+    // with the manual policy, `compact` drops values which expired while the store was open
+    store.set_with_ttl("key102", "value", Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "session", "token", "--ttl", "30s"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["ttl", "session"]).success().stdout(eq("30s\n"));
+    kvs(&["set", "key1", "value1"]).success();
+    kvs(&["ttl", "key1"]).success().stdout(eq("No expiry\n"));
+    kvs(&["ttl", "key2"])
+        .failure()
+        .stdout(eq("Key not found\n"));
+    kvs(&["set", "key1", "value1", "--ttl", "soon"])
+        .failure()
+        .stderr(contains("Invalid TTL"));
+
+    kvs(&["set", "short", "value", "--ttl", "100ms"]).success();
+    thread::sleep(Duration::from_millis(200));
+    kvs(&["get", "short"])
+        .success()
+        .stdout(eq("Key not found\n"));
+    kvs(&["get", "session"]).success().stdout(eq("token\n"));
+}
+
+// A snapshot keeps reading the store as it was when taken.
+#[test]
+fn snapshot_point_in_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+
+    let snapshot = store.snapshot();
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+    store.set("key10".to_owned(), "new".to_owned())?;
+
+    assert_eq!(snapshot.get("key0")?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key9")?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key10")?, None);
+    assert_eq!(snapshot.len(), 10);
+    let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 10);
+    assert!(pairs.iter().all(|(_, value)| value == "old"));
+    let keys = snapshot
+        .scan("key2".."key4")
+        .keys()
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["key2", "key3"]);
+    assert_eq!(snapshot.scan_prefix("key1").count(), 1);
+    assert_eq!(snapshot.scan("key5".."key1").count(), 0);
+
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    assert_eq!(store.len(), 10);
+    Ok(())
+}
+
+// Compaction keeps the logs a snapshot reads from until the snapshot is dropped.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+
+    let snapshot = store.snapshot();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(temp_dir.path().join("1.log.stale").exists());
+
+    let second = store.snapshot();
+    store.compact()?;
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+        assert_eq!(
+            second.get(format!("key{}", key_id))?,
+            Some("new".to_owned())
+        );
+    }
+    assert!(temp_dir.path().join("2.log.stale").exists());
+
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log.stale").exists());
+    assert!(temp_dir.path().join("2.log.stale").exists());
+
+    // the directory stays locked while a snapshot is alive
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(second);
+    assert!(!temp_dir.path().join("2.log.stale").exists());
+
+    // logs kept for snapshots of a store which was not closed are deleted on open
+    fs::write(temp_dir.path().join("1.log.stale"), "stale")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("1.log.stale").exists());
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// The versions of a key, as sequence numbers and values.
+fn versions(store: &KvStore, key: &str) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+    Ok(store
+        .history(key)?
+        .into_iter()
+        .map(|version| (version.seq, version.value))
+        .collect())
+}
+
+// Old versions kept by the retention policy can be read back, through compactions and
+// reopening the store.
+#[test]
+fn history_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .retention(Retention::Versions(10));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value3");
+    store.write(batch)?;
+    assert_eq!(store.last_seq(), 5);
+
+    let expected = vec![
+        (1, Some(b"value1".to_vec())),
+        (3, Some(b"value2".to_vec())),
+        (4, None),
+        (5, Some(b"value3".to_vec())),
+    ];
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(versions(store, "key1")?, expected);
+        assert_eq!(store.get_at("key1", 0)?, None);
+        assert_eq!(store.get_at("key1", 1)?, Some("value1".to_owned()));
+        assert_eq!(store.get_at("key1", 2)?, Some("value1".to_owned()));
+        assert_eq!(store.get_at("key1", 3)?, Some("value2".to_owned()));
+        assert_eq!(store.get_at("key1", 4)?, None);
+        assert_eq!(store.get_at("key1", 5)?, Some("value3".to_owned()));
+        assert_eq!(store.get_at("other", 5)?, Some("value".to_owned()));
+        assert_eq!(store.get_at("missing", 5)?, None);
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    assert_eq!(store.last_seq(), 5);
+    store.compact()?;
+    drop(store);
+    // from the hint file of the compaction, and from replaying its log
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    // a store keeping no old version only has the current one
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        versions(&store, "key1")?,
+        vec![(5, Some(b"value3".to_vec()))]
+    );
+    assert_eq!(store.get_at("key1", 3)?, None);
+    let time = store.history("key1")?[0].time;
+    assert!(time <= SystemTime::now() && time > SystemTime::now() - Duration::from_secs(60));
+    Ok(())
+}
+
+// Sequence numbers go on from the last command, even when compaction dropped its record.
+#[test]
+fn last_seq_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Manual);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.last_seq(), 3);
+    drop(store);
+
+    assert_eq!(KvStore::open_read_only(temp_dir.path())?.last_seq(), 3);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.last_seq(), 3);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.last_seq(), 4);
+    assert_eq!(store.history("key2")?[0].seq, 4);
+    Ok(())
+}
+
+// Compaction drops the old versions beyond the retention count.
+#[test]
+fn retention_by_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .retention(Retention::Versions(2));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for value in 1..=5 {
+        store.set("key1".to_owned(), format!("value{}", value))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.history("key1")?.len(), 5);
+
+    store.compact()?;
+    let kept: Vec<_> = (3..=5)
+        .map(|seq| (seq, Some(format!("value{}", seq).into_bytes())))
+        .collect();
+    assert_eq!(versions(&store, "key1")?, kept);
+    assert_eq!(store.get_at("key1", 2)?, None);
+    assert_eq!(store.get_at("key1", 4)?, Some("value4".to_owned()));
+    assert_eq!(
+        versions(&store, "key2")?,
+        vec![(6, Some(b"value".to_vec())), (7, None)]
+    );
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(versions(&store, "key1")?, kept);
+    assert_eq!(store.get_at("key2", 6)?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // keeping fewer versions drops the others at the next compaction
+    let options = options.retention(Retention::Versions(0));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    assert_eq!(
+        versions(&store, "key1")?,
+        vec![(5, Some(b"value5".to_vec()))]
+    );
+    assert_eq!(versions(&store, "key2")?, vec![]);
+    Ok(())
+}
+
+// Compaction drops the old versions superseded before the retention window.
+#[test]
+fn retention_by_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .retention(Retention::Age(Duration::from_millis(500)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    store.compact()?;
+    // value2 was superseded just now, and key2 was removed too long ago
+    assert_eq!(
+        versions(&store, "key1")?,
+        vec![(2, Some(b"value2".to_vec())), (5, Some(b"value3".to_vec()))]
+    );
+    assert_eq!(versions(&store, "key2")?, vec![]);
+
+    thread::sleep(Duration::from_millis(600));
+    store.compact()?;
+    assert_eq!(
+        versions(&store, "key1")?,
+        vec![(5, Some(b"value3".to_vec()))]
+    );
+    Ok(())
+}
+
+// An expired value stays expired after compaction, rather than an old version it superseded
+// coming back.
+#[test]
+fn retention_with_expired_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .retention(Retention::Versions(2));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key1", "value2", Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let expected = vec![(1, Some(b"value1".to_vec())), (2, Some(b"value2".to_vec()))];
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(versions(&store, "key1")?, expected);
+    drop(store);
+
+    // again once the store was loaded from the compacted log
+    for _ in 0..2 {
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get_at("key1", 1)?, Some("value1".to_owned()));
+        assert_eq!(versions(&store, "key1")?, expected);
+        store.compact()?;
+    }
+    Ok(())
+}
+
+// A checkpoint taken while writes go on opens as the store at that point.
+#[test]
+fn checkpoint_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .max_log_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.compact()?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), "newer".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup");
+    store.checkpoint(&backup)?;
+    writer.join().unwrap()?;
+    assert!(backup.join("format").exists());
+    assert!(matches!(
+        store.checkpoint(&backup),
+        Err(KvsError::StoreExists)
+    ));
+
+    // every key has one of the values it had while the checkpoint was taken, and the keys
+    // written after one written by then have its new value too
+    let copy = KvStore::open(&backup)?;
+    assert_eq!(copy.len(), 100);
+    let values: Vec<_> = (0..100)
+        .map(|key_id| copy.get(format!("key{}", key_id)).unwrap().unwrap())
+        .collect();
+    let newer = values.iter().take_while(|value| *value == "newer").count();
+    for (key_id, value) in values.iter().enumerate().skip(newer) {
+        let before = if key_id < 50 { "new" } else { "old" };
+        assert_eq!(value, before);
+    }
+    copy.set("key0".to_owned(), "copy".to_owned())?;
+    copy.compact()?;
+    drop(copy);
+
+    // the store is left as is by the writes to its copy
+    assert_eq!(store.get("key0".to_owned())?, Some("newer".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("newer".to_owned()));
+    Ok(())
+}
+
+// A checkpoint restored over a store replaces it.
+#[test]
+fn restore_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert!(matches!(
+        KvStore::restore(backup_dir.path(), temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(store);
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::restore(empty_dir.path(), temp_dir.path()),
+        Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+    ));
+    // a copy which fails leaves the store as it was
+    let blocker = temp_dir.path().join("restoring");
+    fs::write(&blocker, "not a directory")?;
+    assert!(KvStore::restore(backup_dir.path(), temp_dir.path()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    fs::remove_file(blocker)?;
+
+    fs::write(temp_dir.path().join("notes.txt"), "kept")?;
+    KvStore::restore(backup_dir.path(), temp_dir.path())?;
+    assert!(!temp_dir.path().join("restoring").exists());
+    assert!(temp_dir.path().join("notes.txt").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // the checkpoint itself is left untouched
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A restore stopped by a crash after its copy was complete is finished by the next writable
+// open, and read-only opens refuse the directory meanwhile.
+#[test]
+fn unfinished_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // the complete copy, whose log was already moved in place
+    let copy = temp_dir.path().join("restoring");
+    fs::create_dir(&copy)?;
+    let mut manifest = String::new();
+    for entry in fs::read_dir(backup_dir.path())? {
+        let name = entry?.file_name().into_string().unwrap();
+        fs::copy(backup_dir.path().join(&name), copy.join(&name))?;
+        manifest.push_str(&name);
+        manifest.push('\n');
+    }
+    fs::write(copy.join("MANIFEST"), manifest)?;
+    fs::rename(copy.join("1.log"), temp_dir.path().join("1.log"))?;
+
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::UnfinishedRestore)
+    ));
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!copy.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "key1", "value1"]).success();
+    kvs(&["backup", backup.to_str().unwrap()])
+        .success()
+        .stdout(is_empty());
+    kvs(&["backup", backup.to_str().unwrap()])
+        .failure()
+        .stderr(contains("already holds a store"));
+    kvs(&["set", "key1", "value2"]).success();
+
+    // a backup reads the store while another process writes to it
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let live_backup = backup_dir.path().join("live");
+    kvs(&["backup", live_backup.to_str().unwrap()]).success();
+    drop(store);
+    let store = KvStore::open(&live_backup).unwrap();
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    drop(store);
+
+    // a backup which failed midway leaves nothing in the way of the next one
+    let retried = backup_dir.path().join("retried");
+    fs::create_dir(backup_dir.path().join(".retried.tmp")).unwrap();
+    fs::write(backup_dir.path().join(".retried.tmp/1.log"), "partial").unwrap();
+    kvs(&["backup", retried.to_str().unwrap()]).success();
+    assert!(!backup_dir.path().join(".retried.tmp").exists());
+    let store = KvStore::open(&retried).unwrap();
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    drop(store);
+
+    kvs(&["restore", backup.to_str().unwrap()])
+        .success()
+        .stdout(is_empty());
+    kvs(&["get", "key1"]).success().stdout(eq("value1\n"));
+    kvs(&[
+        "restore",
+        backup_dir.path().join("missing").to_str().unwrap(),
+    ])
+    .failure()
+    .stderr(contains("No store in"));
+
+    // the backup is a data directory of the kvs engine
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--engine", "sled"])
+        .current_dir(&backup)
+        .assert()
+        .failure();
+}
+
+// Export and import round-trip every pair, including keys and values which are not UTF-8
+// or need quoting.
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (b"a,b".to_vec(), b"say \"hi\"\r\nbye".to_vec()),
+        (b"back\\slash".to_vec(), b"\\x41".to_vec()),
+        (b"bytes".to_vec(), vec![0, 0xff, b'x', 0xc3]),
+        (vec![0xfe, b'k'], "caf\u{e9}".as_bytes().to_vec()),
+        (b"empty".to_vec(), Vec::new()),
+    ];
+    for (key, value) in &pairs {
+        store.set_bytes(key, value)?;
+    }
+    for key_id in 0..2500 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+
+    for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+        let mut exported = Vec::new();
+        assert_eq!(store.export("", format, &mut exported)?, 2505);
+        let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+        let copy = KvStore::open(copy_dir.path())?;
+        assert_eq!(copy.import(format, &exported[..])?, 2505);
+        let all = |store: &KvStore| -> Result<Vec<_>> { store.iter().bytes().collect() };
+        assert_eq!(all(&copy)?, all(&store)?);
+
+        let mut exported = Vec::new();
+        assert_eq!(store.export("key000", format, &mut exported)?, 10);
+        assert_eq!(exported.iter().filter(|&&byte| byte == b'\n').count(), 10);
+    }
+
+    let mut exported = Vec::new();
+    store.export("a,", ExportFormat::Csv, &mut exported)?;
+    assert_eq!(exported, b"\"a,b\",\"say \"\"hi\"\"\r\nbye\"\n");
+    let mut exported = Vec::new();
+    store.export("bytes", ExportFormat::Csv, &mut exported)?;
+    assert_eq!(exported, b"bytes,\0\\xFFx\\xC3\n".to_vec());
+    let mut exported = Vec::new();
+    store.export("bytes", ExportFormat::JsonLines, &mut exported)?;
+    assert_eq!(exported, b"{\"key\":\"bytes\",\"value\":[0,255,120,195]}\n");
+
+    // pairs before a bad line are imported, by whole batches
+    let bad = "{\"key\":\"k1\",\"value\":\"v1\"}\n\n{\"key\":\"k2\"}\n";
+    assert!(matches!(
+        store.import(ExportFormat::JsonLines, bad.as_bytes()),
+        Err(KvsError::InvalidImport { line: 3, .. })
+    ));
+    assert_eq!(store.get("k1".to_owned())?, None);
+    let bad = "k1,v1\n\"k2,v2\n";
+    assert!(matches!(
+        store.import(ExportFormat::Csv, bad.as_bytes()),
+        Err(KvsError::InvalidImport { line: 2, .. })
+    ));
+    assert!(matches!(
+        store.import(ExportFormat::Csv, "k1,\\q\n".as_bytes()),
+        Err(KvsError::InvalidImport { line: 1, .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "key2", "value2"]).success();
+    kvs(&["set", "key1", "value,1"]).success();
+    kvs(&["set", "other", "value"]).success();
+    kvs(&["export"]).success().stdout(eq(concat!(
+        "{\"key\":\"key1\",\"value\":\"value,1\"}\n",
+        "{\"key\":\"key2\",\"value\":\"value2\"}\n",
+        "{\"key\":\"other\",\"value\":\"value\"}\n",
+    )));
+    kvs(&["export", "--format", "csv", "--prefix", "key"])
+        .success()
+        .stdout(eq("key1,\"value,1\"\nkey2,value2\n"));
+    kvs(&["export", "--format", "xml"]).failure();
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let import = |format: &str, input: &str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["import", "--format", format])
+            .current_dir(&import_dir)
+            .with_stdin()
+            .buffer(input)
+            .assert()
+    };
+    import("csv", "key1,\"value,1\"\nkey2,value2\n")
+        .success()
+        .stdout(is_empty());
+    import("jsonl", "{\"key\":\"key3\",\"value\":[118,51]}\n").success();
+    import("jsonl", "not json\n")
+        .failure()
+        .stderr(contains("Invalid pair on line 1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&import_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1\tvalue,1\nkey2\tvalue2\nkey3\tv3\n"));
+}
+
+fn assert_send_sync<T: Clone + Send + Sync>() {}
+
+#[test]
+fn kv_store_is_send_sync() {
+    assert_send_sync::<KvStore>();
+}
+
+// Many threads writing to clones of one store at the same time.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..1000 {
+                    store
+                        .set(format!("key{}_{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Readers keep seeing consistent values while a writer overwrites keys and triggers compactions.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..300 {
+                for key_id in 0..100 {
+                    store
+                        .set(format!("key{}", key_id), format!("{}", iter))
+                        .unwrap();
+                }
+            }
+        })
+    };
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..10000 {
+                    let value = store.get(format!("key{}", i % 100)).unwrap();
+                    assert!(value.unwrap().parse::<u32>().is_ok());
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("299".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sled_get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = SledKvsEngine::new(reopen_sled(temp_dir.path())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Opens the sled database at `path` once the last handle closed it. Sled's background flusher
+// keeps the directory locked for a moment after the handles are dropped.
+fn reopen_sled(path: &std::path::Path) -> Result<sled::Db> {
+    for _ in 0..100 {
+        if let Ok(db) = sled::open(path) {
+            return Ok(db);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    Ok(sled::open(path)?)
+}
+
+#[test]
+fn sled_remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
 }